@@ -0,0 +1,94 @@
+//! Time common queries against hand-written traversals
+//!
+//! Run with `cargo run --release --example bench`.  Each query runs
+//! as a selector, as a `Plan` and as a compiled `Program`, next to a
+//! loop over the document doing the same work by hand, which is the
+//! cost of the query without any of the machinery of the library.
+
+extern crate jlens;
+extern crate rustc_serialize;
+
+use jlens::*;
+use rustc_serialize::json::{Json,Object};
+use std::time::{Duration,Instant};
+
+/// Number of objects in the document
+const OBJECTS: usize = 200_000;
+
+/// Number of times each query runs, keeping the fastest time
+const RUNS: usize = 5;
+
+/// Build a list of objects with a few fields each
+fn document() -> Json {
+    Json::Array((0..OBJECTS as u64).map(|i| {
+        let mut tag = Object::new();
+        tag.insert("x".to_string(), Json::U64(i));
+        let mut o = Object::new();
+        o.insert("id".to_string(), Json::U64(i));
+        o.insert("name".to_string(), Json::String(format!("item{}", i)));
+        o.insert("tags".to_string(), Json::Array(vec![Json::Null, Json::Boolean(i % 2 == 0), Json::Object(tag)]));
+        Json::Object(o)
+    }).collect())
+}
+
+/// Return the fastest of `RUNS` runs of `f` and the number of
+/// results of the last
+fn time<F:FnMut() -> usize>(mut f: F) -> (Duration, usize) {
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        count = f();
+        best = best.min(start.elapsed());
+    }
+    (best, count)
+}
+
+/// Time selector `sel` run in each form, next to `baseline`
+fn bench<S,F>(json: &Json, sel: S, baseline: F)
+    where S: Selector + ToPlan<Output=Plan> + Clone + std::fmt::Display, F: Fn(&Json) -> usize {
+    let plan = sel.to_plan();
+    let program = plan.compile();
+    let (base, expected) = time(|| baseline(json));
+    println!("{}", sel);
+    println!("  {:<10}{:>10.1?}", "baseline", base);
+    let runs: [(&str, &dyn Fn() -> usize); 4] = [
+        ("selector", &|| json.query(sel.clone()).len()),
+        ("plan", &|| json.query(plan.clone()).len()),
+        ("program", &|| json.query(program.clone()).len()),
+        ("limited", &|| json.try_query(sel.clone()).map(|r| r.len()).unwrap_or(0))
+    ];
+    for &(name, run) in runs.iter() {
+        let (t, count) = time(run);
+        assert_eq!(count, expected, "{} selected the wrong number of nodes", name);
+        println!("  {:<10}{:>10.1?}{:>8.2}x", name, t, t.as_secs_f64() / base.as_secs_f64());
+    }
+}
+
+fn main() {
+    let json = document();
+
+    bench(&json, child().key("tags").at(2).key("x").uint64(), |json| {
+        json.as_array().unwrap().iter()
+            .filter_map(|o| o.find("tags")?.as_array()?.get(2)?.find("x")?.as_u64())
+            .count()
+    });
+    bench(&json, list().child().key("name").string(), |json| {
+        json.as_array().unwrap().iter().filter(|o| o.find("name").is_some_and(Json::is_string)).count()
+    });
+    bench(&json, child().wherein(key("tags").at(1).boolean().equals(true)), |json| {
+        json.as_array().unwrap().iter()
+            .filter(|o| o.find("tags").and_then(|t| t.as_array()?.get(1)?.as_boolean()) == Some(true))
+            .count()
+    });
+    bench(&json, descend().uint64(), |json| {
+        fn count(j: &Json) -> usize {
+            match *j {
+                Json::Array(ref a) => a.iter().map(|j| j.is_u64() as usize + count(j)).sum(),
+                Json::Object(ref o) => o.values().map(|j| j.is_u64() as usize + count(j)).sum(),
+                _ => 0
+            }
+        }
+        count(json)
+    });
+}
@@ -63,25 +63,89 @@
 //! The `JsonExt` trait provides a convenience method on `Json`
 //! objects which runs a selector and returns a `Vec<&Json>` of
 //! results.
+//!
+//! Selectors are plain values: they implement `Clone`, `Debug` and
+//! `PartialEq`, and are `Send` and `Sync`, whenever the selectors and
+//! closures they are built from do.  A query can therefore be built
+//! once, stored, and run many times from any thread.
+//!
+//! Selectors also implement `Display`, which writes them as the Rust
+//! source of the method chain that builds them, using the shorthand
+//! functions where possible: the query above is written
+//! `list().child().wherein(key("foo").list().child().or(string().equals("Hello, world!"), uint64().equals(42)))`.
+//! Strings are written as Rust string literals, floats which aren't
+//! finite as constants such as `f64::NAN`, and `Json` operands as
+//! `Json::from_str` on their JSON text, so that the output compiles
+//! to an equal selector.  Closures given to `filter`, `filter_path`
+//! and `flat_map` can't be written out and appear as `..`, so only
+//! selectors without closures can be reproduced from their output.
+//! There is no parser: the output is meant to be read or pasted
+//! into Rust code.
+//!
+//! The `ToPlan` trait converts a selector into a `Plan`, which lists
+//! its stages as plain `Op` values.  Plans can be inspected and built
+//! at runtime, and `Plan::analyze` infers the kinds of nodes each
+//! stage may select to report stages which can never select anything,
+//! such as the `uint64()` in `string().uint64()`.
+//! Plans are selectors too, and `Plan::optimize` simplifies them
+//! before they run, for example rewriting `child().parent()` to
+//! `wherein(child())`.
+//! `Plan::compile` goes further and translates a plan into a
+//! `Program` of instructions run by an interpreter, which selects the
+//! same nodes without any of the generic types of the selector it came
+//! from.
 
 #![crate_type = "rlib"]
 
 extern crate rustc_serialize as serialize;
 
-use serialize::json::Json;
-use std::collections::hash_set;
+use serialize::json::{Json,ToJson};
+use std::borrow::Cow;
+use std::cell::{Cell,RefCell};
+use std::collections::{btree_map,hash_map,hash_set};
+use std::hash::{Hash,Hasher};
+use std::ops::Bound::{Excluded,Unbounded};
+use std::cmp::{Ordering,Reverse};
+use std::{error,fmt,iter,ptr,slice};
+use std::time::{Duration,Instant};
+
+use JsonPath::{Root,Descendant,Nested};
 
-use JsonPath::{Root,Descendant};
+mod plan;
+mod vm;
+
+pub use plan::{Analysis,Closure,ExprPlan,Kind,Kinds,NodeMapper,NodePredicate,Op,PathPredicate,Plan,ToPlan,Warning};
+pub use vm::Program;
+
+/// Location of a node within its parent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Step<'a> {
+    /// Element at the given index of a `Json::Array`
+    Index(usize),
+    /// Value for the given key of a `Json::Object`
+    Key(&'a str),
+    /// Node made up by a selector such as `and`, which isn't found in
+    /// its parent at all
+    Synthetic
+}
 
 /// JSON node path
 ///
-/// Represents a path to a JSON node.
-#[derive(Clone, Copy)]
+/// Represents a path to a JSON node.  Paths may be represented in
+/// more ways in the future, so prefer `node`, `step` and `parent` to
+/// matching on the variants.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
 pub enum JsonPath<'a:'b,'b> {
     /// At the root node
     Root(&'a Json),
-    /// At a node with the given parent path
-    Descendant(&'a Json, &'b JsonPath<'a,'b>)
+    /// At a node with the given location in the given parent path
+    Descendant(&'a Json, Step<'a>, &'b JsonPath<'a,'b>),
+    /// At a node with the given location below the given base path,
+    /// with the ancestors between the base and the node and their
+    /// locations listed outermost first
+    Nested(&'a Json, Step<'a>, &'b [(&'a Json, Step<'a>)], &'b JsonPath<'a,'b>)
 }
 
 impl<'a,'b> JsonPath<'a,'b> {
@@ -91,690 +155,4413 @@ impl<'a,'b> JsonPath<'a,'b> {
         Root(r)
     }
 
-    /// Create descendant path of self at node `child`
+    /// Create descendant path of self at node `child` found at `step`
     #[inline]
-    fn descendant(&'b self, child: &'a Json) -> JsonPath<'a,'b> {
-        Descendant(child, self)
+    fn descendant(&'b self, child: &'a Json, step: Step<'a>) -> JsonPath<'a,'b> {
+        visit_node();
+        Descendant(child, step, self)
     }
 
     /// Return the node this path points to
     #[inline]
-    fn node(&self) -> &'a Json {
+    pub fn node(&self) -> &'a Json {
         match *self {
             Root(n) => n,
-            Descendant(n, _) => n
+            Descendant(n, _, _) => n,
+            Nested(n, _, _, _) => n
         }
     }
 
-    /// Return the parent path if this is not the root, otherwise `None`
+    /// Return the location of the node in its parent if this is not
+    /// the root, otherwise `None`
     #[inline]
-    fn parent(&self) -> Option<&'b JsonPath<'a,'b>> {
+    pub fn step(&self) -> Option<Step<'a>> {
         match *self {
             Root(..) => None,
-            Descendant(_, p) => Some(p)
+            Descendant(_, s, _) => Some(s),
+            Nested(_, s, _, _) => Some(s)
         }
     }
-}
-
-/// JSON selector trait
-///
-/// Implementors of this trait select nodes from `Json` objects
-/// according to some criteria.
-pub trait Selector: Sized {
-    /// Select matching nodes
-    ///
-    /// Given the path to a single node, `input`, this method should
-    /// identify nodes to be selected and invoke the closure `f` with
-    /// a path to each.
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>);
 
-    /// Select current node if it is a `Json::Boolean`
-    #[inline]
-    fn boolean(self) -> BooleanSel<Self> {
-        BooleanSel { inner: self }
+    /// Return the index of the node in its parent and the length of
+    /// the parent if the parent is a `Json::Array`, otherwise `None`
+    fn position(&self) -> Option<(usize, usize)> {
+        match (self.parent().map(|p| p.node()), self.step()) {
+            (Some(Json::Array(v)), Some(Step::Index(i)))
+                if i < v.len() && ptr::eq(&v[i], self.node()) => Some((i, v.len())),
+            _ => None
+        }
     }
 
-    /// Select current node if it is a `Json::U64`
-    #[inline]
-    fn uint64(self) -> U64Sel<Self> {
-        U64Sel { inner: self }
+    /// Return the number of levels the node is below the root
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut current = *self;
+        loop {
+            match current {
+                Root(..) => return depth,
+                Descendant(_, _, p) => {
+                    depth += 1;
+                    current = *p
+                },
+                Nested(_, _, ancestors, base) => {
+                    depth += ancestors.len() + 1;
+                    current = *base
+                }
+            }
+        }
     }
 
-    /// Select current node if it is a `Json::I64`
+    /// Return the parent path if this is not the root, otherwise `None`
     #[inline]
-    fn int64(self) -> I64Sel<Self> {
-        I64Sel { inner: self }
+    pub fn parent(&self) -> Option<JsonPath<'a,'b>> {
+        match *self {
+            Root(..) => None,
+            Descendant(_, _, p) => Some(*p),
+            Nested(_, _, ancestors, base) => match ancestors.split_last() {
+                Some((&(p, s), rest)) => Some(Nested(p, s, rest, base)),
+                None => Some(*base)
+            }
+        }
     }
+}
 
-    /// Select current node if it is a `Json::F64`
-    #[inline]
-    fn float64(self) -> F64Sel<Self> {
-        F64Sel { inner: self }
+impl<'a,'b> fmt::Display for JsonPath<'a,'b> {
+    /// Write the path in JSONPath notation, such as `$.foo[0]`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut steps = Vec::new();
+        let mut current = *self;
+        while let (Some(s), Some(p)) = (current.step(), current.parent()) {
+            steps.push(s);
+            current = p
+        }
+        f.write_str("$")?;
+        for s in steps.iter().rev() {
+            match *s {
+                Step::Index(i) => write!(f, "[{}]", i)?,
+                Step::Key(k) if !k.is_empty() &&
+                    k.chars().all(|c| c == '_' || c.is_ascii_alphanumeric()) &&
+                    !k.starts_with(|c: char| c.is_ascii_digit()) => write!(f, ".{}", k)?,
+                Step::Key(k) => write!(f, "[{:?}]", k)?,
+                // Synthetic nodes are written as the path they were
+                // made from
+                Step::Synthetic => ()
+            }
+        }
+        Ok(())
     }
+}
 
-    /// Select current node if it is a `Json::String`
-    #[inline]
-    fn string(self) -> StringSel<Self> {
-        StringSel { inner: self }
-    }
+/// Query error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A traversal went deeper than the maximum depth
+    DepthLimit(usize),
+    /// The query visited more than the maximum number of nodes
+    NodeLimit(usize),
+    /// The query selected more than the maximum number of results
+    ResultLimit(usize),
+    /// Filters were nested deeper than the maximum filter depth
+    FilterDepthLimit(usize),
+    /// Sets of nodes used to remove duplicates took more than the
+    /// maximum number of bytes
+    SetMemoryLimit(usize)
+}
 
-    /// Select current node if it is a `Json::Object`
-    #[inline]
-    fn object(self) -> ObjectSel<Self> {
-        ObjectSel { inner: self }
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::DepthLimit(d) => write!(f, "maximum traversal depth of {} exceeded", d),
+            Error::NodeLimit(n) => write!(f, "maximum of {} nodes visited exceeded", n),
+            Error::ResultLimit(n) => write!(f, "maximum of {} results exceeded", n),
+            Error::FilterDepthLimit(d) => write!(f, "maximum filter depth of {} exceeded", d),
+            Error::SetMemoryLimit(n) => write!(f, "maximum set memory of {} bytes exceeded", n)
+        }
     }
+}
 
-    /// Select current node if it is a `Json::Array`
-    #[inline]
-    fn list(self) -> ListSel<Self> {
-        ListSel { inner: self }
-    }
+impl error::Error for Error {}
 
-    /// Select current node if it is a `Json::Null`
-    #[inline]
-    fn null(self) -> NullSel<Self> {
-        NullSel { inner: self }
-    }
+/// Default maximum traversal depth
+pub const DEFAULT_MAX_DEPTH: usize = 1024;
 
-    /// Select list element
-    ///
-    /// If the current node is a `Json::Array` of at least `index + 1`
-    /// elements, selects the element at `index`.  Otherwise no nodes
-    /// are selected.
-    #[inline]
-    fn at(self, index: usize) -> At<Self> {
-        At { inner: self, index: index }
-    }
+/// Query execution limits
+///
+/// Bounds the work a query may perform.  Pass to
+/// `JsonExt::try_query_with` to run a query with limits other
+/// than the defaults.
+///
+/// Only the maximum traversal depth is limited by default.  The
+/// other limits make a budget for running queries from untrusted
+/// sources, which could otherwise take time and memory out of all
+/// proportion to the document: `descend().wherein(descend().null())`
+/// walks the subtree of every node in it.  A query which exceeds
+/// one of them is aborted, leaving its results incomplete.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    max_depth: usize,
+    max_nodes: usize,
+    max_results: usize,
+    max_filter_depth: usize,
+    max_set_memory: usize
+}
 
-    /// Select object value for key
-    ///
-    /// If the current node is a `Json::Object` that contains the key
-    /// `name`, its value is selected.  Otherwise no nodes are
-    /// selected.
+impl Limits {
+    /// Create default limits
     #[inline]
-    fn key(self, name: &str) -> Key<Self> {
-        Key { inner: self, name: name }
+    pub fn new() -> Limits {
+        Limits {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_nodes: usize::MAX,
+            max_results: usize::MAX,
+            max_filter_depth: usize::MAX,
+            max_set_memory: usize::MAX
+        }
     }
 
-    /// Select children of current node
+    /// Set maximum traversal depth
     ///
-    /// Selects all immediate child nodes of the current node: all
-    /// elements of a `Json::Array`, or all values of a `Json::Object`.
+    /// Selectors such as `descend()` which walk a subtree will not
+    /// visit nodes more than `depth` levels below the node they
+    /// started from, and will report `Error::DepthLimit` if such
+    /// nodes exist.
     #[inline]
-    fn child(self) -> Child<Self> {
-        Child { inner: self }
+    pub fn max_depth(mut self, depth: usize) -> Limits {
+        self.max_depth = depth;
+        self
     }
 
-    /// Select parent of current node
+    /// Set maximum number of nodes visited
     ///
-    /// Selects the parent of the current node if it is not the root,
-    /// otherwise no nodes are selected.
+    /// Every step of a selector into a child node counts as a visit,
+    /// including repeated visits of the same node.  So does every
+    /// pair of values an expression combines or compares, and every
+    /// pair of elements compared by deep equality.  The query is
+    /// aborted with `Error::NodeLimit` after `nodes` visits.
     #[inline]
-    fn parent(self) -> Parent<Self> {
-        Parent { inner: self }
+    pub fn max_nodes(mut self, nodes: usize) -> Limits {
+        self.max_nodes = nodes;
+        self
     }
 
-    /// Select descendents of current node
+    /// Set maximum number of results
     ///
-    /// Selects all child nodes of the current node and all their
-    /// children, recursively.
+    /// The query is aborted with `Error::ResultLimit` when it
+    /// selects more than `results` nodes.
     #[inline]
-    fn descend(self) -> Descend<Self> {
-        Descend { inner: self }
+    pub fn max_results(mut self, results: usize) -> Limits {
+        self.max_results = results;
+        self
     }
 
-    /// Select ancestors of current node
+    /// Set maximum filter depth
     ///
-    /// Selects the parent, grandparent, etc. of the current node up
-    /// to the root of the tree.
+    /// Filters given to `wherein`, `descend_until`, `descend_prune`
+    /// and `if_then_else` may contain further filters.  The query is
+    /// aborted with `Error::FilterDepthLimit` when filters nested
+    /// more than `depth` deep are run.
     #[inline]
-    fn ascend(self) -> Ascend<Self> {
-        Ascend { inner: self }
+    pub fn max_filter_depth(mut self, depth: usize) -> Limits {
+        self.max_filter_depth = depth;
+        self
     }
 
-    /// Select current node based on filter
+    /// Set maximum memory of sets of nodes
     ///
-    /// Runs the selector `filter` on the current node.  If it selects
-    /// any nodes, the current node is selected.  If it does not select
-    /// any nodes, no nodes are selected.
+    /// Selectors such as `union` and `distinct` keep sets of the
+    /// nodes they have seen to remove duplicates, and expressions
+    /// such as `compare` and `add` buffer the values of their right
+    /// operand.  The query is aborted with `Error::SetMemoryLimit`
+    /// when the sets and buffers alive at once hold more than `bytes`
+    /// bytes of entries.  This is an estimate counting the size of a
+    /// pointer per entry.
     #[inline]
-    fn wherein<T:Selector>(self, filter: T) -> Wherein<Self,T> {
-        Wherein { inner: self, filter: filter }
+    pub fn max_set_memory(mut self, bytes: usize) -> Limits {
+        self.max_set_memory = bytes;
+        self
     }
+}
 
-    /// Select union of two selectors
-    ///
-    /// Runs `left` and `right` on the current node and selects
-    /// nodes which are selected by either.
-    #[inline]
-    fn union<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> Union<Self,T1,T2> {
-        Union { inner: self, left: left, right: right }
+impl Limits {
+    /// Create limits which never abort a query
+    fn unlimited() -> Limits {
+        Limits::new().max_depth(usize::MAX)
     }
+}
 
-    /// Select intersection of two selectors
-    ///
-    /// Runs `left` and `right` on the current node and selects
-    /// nodes which are selected by both.
-    #[inline]
-    fn intersect<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> Intersect<Self,T1,T2> {
-        Intersect { inner: self, left: left, right: right }
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits::new()
     }
+}
 
-    /// Select symmetric difference of two selectors
-    ///
-    /// Runs `left` and `right` on the current node, selecting
-    /// nodes which are selected by `left` but not selected
-    /// by `right`.
-    ///
-    /// Warning: this selector will execute its parent in the chain
-    /// twice which may result in bad performance.
-    #[inline]
-    fn diff<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> Diff<Self,T1,T2> {
-        Diff { inner: self, left: left, right: right }
-    }
+/// State of the query running on the current thread
+struct Context {
+    limits: Limits,
+    error: Option<Error>,
+    aborted: bool,
+    nodes: usize,
+    results: usize,
+    filter_depth: usize,
+    set_memory: usize,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>
+}
 
-    /// Select logical-and of two selectors
-    ///
-    /// Runs `left` and `right` on the current node and
-    /// selects an arbitrary node if both selected at
-    /// least one node themselves.  This is useful for
-    /// encoding logical-and conditions for `wherein`.
-    #[inline]
-    fn and<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> AndSel<Self,T1,T2> {
-        AndSel { inner: self, left: left, right: right }
+impl Context {
+    fn new(limits: &Limits) -> Context {
+        Context {
+            limits: *limits, error: None, aborted: false,
+            nodes: 0, results: 0, filter_depth: 0, set_memory: 0,
+            tracer: None, profiler: None
+        }
     }
 
-    /// Select logical-or of two selectors
+    /// Abort the query with error `e`
     ///
-    /// Runs `left` and `right` on the current node and
-    /// selects an arbitrary node if either selected at
-    /// least one node themselves.  This is useful for
-    /// encoding logical-and conditions for `wherein`.
-    #[inline]
-    fn or<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> OrSel<Self,T1,T2> {
-        OrSel { inner: self, left: left, right: right }
+    /// This takes precedence over errors which didn't abort it.
+    fn abort(&mut self, e: Error) {
+        if !self.aborted {
+            self.aborted = true;
+            self.error = Some(e)
+        }
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct Node {
-    _dummy: ()
+thread_local!(static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) });
+
+/// How much bookkeeping the current query context does
+///
+/// Plain queries neither enforce limits nor are instrumented, and
+/// check this to skip the bookkeeping below without borrowing the
+/// context.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// No limits to enforce and no instrumentation
+    Plain,
+    /// Limits to enforce
+    Limited,
+    /// A tracer or profiler, and possibly limits to enforce
+    Instrumented
 }
 
-impl Selector for Node {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        f(input)
-    }
+thread_local!(static MODE: Cell<Mode> = const { Cell::new(Mode::Plain) });
+
+/// Run `f` in the query context `context` and return the context
+///
+/// The previous context, if any, is restored afterwards so that
+/// queries may nest.
+fn run_context<F:FnOnce()>(context: Context, f: F) -> Context {
+    let mode = if context.tracer.is_some() || context.profiler.is_some() {
+        Mode::Instrumented
+    } else if context.limits != Limits::unlimited() {
+        Mode::Limited
+    } else {
+        Mode::Plain
+    };
+    let saved = CONTEXT.with(|c| c.replace(Some(context)));
+    let saved_mode = MODE.with(|m| m.replace(mode));
+    f();
+    MODE.with(|m| m.set(saved_mode));
+    CONTEXT.with(|c| c.replace(saved)).expect("query context missing")
 }
 
-pub struct ObjectSel<S> {
-    inner: S
+/// Check whether the current query keeps count of its work
+#[inline]
+fn counting() -> bool {
+    MODE.with(Cell::get) != Mode::Plain
 }
 
-impl<S:Selector> Selector for ObjectSel<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::Object(..) => f(x),
-                _ => ()
-            }
-        })
+/// Run `f` on the current query context if it keeps count of its
+/// work, otherwise return `default`
+#[inline]
+fn count<R,F:FnOnce(&mut Context) -> R>(default: R, f: F) -> R {
+    if counting() {
+        with_current(default, f)
+    } else {
+        default
     }
 }
 
-pub struct ListSel<S> {
-    inner: S
+/// Run `f` on the current query context, or return `default` outside
+/// of one
+///
+/// Kept out of line so that the checks guarding it stay cheap.
+#[cold]
+#[inline(never)]
+fn with_current<R,F:FnOnce(&mut Context) -> R>(default: R, f: F) -> R {
+    CONTEXT.with(|c| match *c.borrow_mut() {
+        Some(ref mut context) => f(context),
+        None => default
+    })
 }
 
-impl<S:Selector> Selector for ListSel<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::Array(..) => f(x),
-                _ => ()
-            }
-        })
-    }
+/// Return the limits of the current query context
+fn limits() -> Limits {
+    count(Limits::unlimited(), |context| context.limits)
 }
 
-pub struct StringSel<S> {
-    inner: S
+/// Check whether the current query has been aborted
+#[inline]
+fn aborted() -> bool {
+    count(false, |context| context.aborted)
 }
 
-pub struct StringEquals<'a,S> {
-    inner: S,
-    comp: &'a str
+/// Count a node visited by the current query
+///
+/// Returns `false` if the query has been aborted, in which case the
+/// caller should stop its traversal.
+#[inline]
+fn visit_node() -> bool {
+    count(true, |context| {
+        context.nodes += 1;
+        if context.nodes > context.limits.max_nodes {
+            let max = context.limits.max_nodes;
+            context.abort(Error::NodeLimit(max))
+        }
+        if let Some(Profiler { current: Some(i), ref mut selectors, .. }) = context.profiler {
+            selectors[i].nodes_visited += 1
+        }
+        !context.aborted
+    })
 }
 
-impl<S:Selector> StringSel<S> {
-    /// Select current `Json::String` node if it is equal to `comp`
-    #[inline]
-    pub fn equals(self, comp: &str) -> StringEquals<S> {
-        let StringSel { inner } = self;
-        StringEquals { inner: inner, comp: comp }
-    }
+/// Count a result of the current query
+///
+/// Returns `false` if the result exceeds the maximum number of
+/// results, in which case it should be left out.
+#[inline]
+fn admit_result() -> bool {
+    count(true, |context| {
+        if context.aborted {
+            return false
+        }
+        context.results += 1;
+        if context.results > context.limits.max_results {
+            let max = context.limits.max_results;
+            context.abort(Error::ResultLimit(max))
+        }
+        !context.aborted
+    })
 }
 
-impl<S:Selector> Selector for StringSel<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::String(..) => f(x),
-                _ => ()
-            }
-        })
-    }
+/// Account for a change of `delta` bytes in the memory of sets of
+/// nodes alive in the current query
+#[inline]
+fn track_set_memory(delta: isize) {
+    count((), |context| {
+        context.set_memory = context.set_memory.saturating_add_signed(delta);
+        if context.set_memory > context.limits.max_set_memory {
+            let max = context.limits.max_set_memory;
+            context.abort(Error::SetMemoryLimit(max))
+        }
+    })
 }
 
-impl<'s,S:Selector> Selector for StringEquals<'s,S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::String(ref s) if self.comp == *s => f(x),
-                _ => ()
-            }
-        })
-    }
+/// Record an error in the current query context
+///
+/// Only the first error is kept.  Outside of a query context this
+/// does nothing.
+fn raise(e: Error) {
+    CONTEXT.with(|c| if let Some(ref mut context) = *c.borrow_mut() {
+        if context.error.is_none() {
+            context.error = Some(e)
+        }
+    })
 }
 
-pub struct BooleanSel<S> {
-    inner: S
-}
+/// Maximum number of rejected paths a trace keeps per selector
+pub const TRACE_SAMPLES: usize = 5;
 
-pub struct BooleanEquals<S> {
-    inner: S,
-    comp: bool
+/// Trace of a query
+///
+/// Produced by `JsonExt::trace`, this records for each selector in
+/// the query how many nodes it received, how many it selected, and
+/// the paths of up to `TRACE_SAMPLES` received nodes for which it
+/// selected nothing.  The nodes a selector receives are those
+/// selected by the selector before it in the chain, or for `node()`
+/// the paths it was run on.  Selectors which combine their results
+/// such as `and` instead reject the paths they were run on.
+///
+/// A trace forms a tree with a child for each selector a selector
+/// ran, mirroring the structure of the query: the previous selector
+/// in the chain comes first, followed by the filters and operands,
+/// while the root stands for the query as a whole.  Its `Display`
+/// implementation prints the tree with one selector per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trace {
+    name: &'static str,
+    input: usize,
+    output: usize,
+    rejected: Vec<String>,
+    children: Vec<Trace>
 }
 
-impl<S:Selector> BooleanSel<S> {
-    /// Select current `Json::Boolean` node if it is equal to `comp`
-    #[inline]
-    pub fn equals(self, comp: bool) -> BooleanEquals<S> {
-        let BooleanSel { inner } = self;
-        BooleanEquals { inner: inner, comp: comp }
+impl Trace {
+    /// Return the name of the selector type
+    pub fn name(&self) -> &str {
+        self.name
     }
-}
 
-impl<S:Selector> Selector for BooleanSel<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::Boolean(..) => f(x),
-                _ => ()
-            }
-        })
+    /// Return the number of nodes the selector received
+    pub fn input(&self) -> usize {
+        self.input
     }
-}
 
-impl<S:Selector> Selector for BooleanEquals<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::Boolean(b) if b == self.comp => f(x),
-                _ => ()
-            }
-        })
+    /// Return the number of nodes the selector selected
+    pub fn output(&self) -> usize {
+        self.output
     }
-}
 
-pub struct U64Sel<S> {
-    inner: S
-}
+    /// Return paths of nodes the selector received but selected
+    /// nothing for, in JSONPath notation
+    pub fn rejected(&self) -> &[String] {
+        &self.rejected
+    }
 
-pub struct U64Equals<S> {
-    inner: S,
-    comp: u64
-}
+    /// Return traces of the selectors this selector ran
+    pub fn children(&self) -> &[Trace] {
+        &self.children
+    }
 
-impl<S:Selector> U64Sel<S> {
-    #[inline]
-    pub fn equals(self, comp: u64) -> U64Equals<S> {
-        let U64Sel { inner } = self;
-        U64Equals { inner: inner, comp: comp }
+    fn fmt_indented(&self, f: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        writeln!(f, "{:indent$}{}: {} in, {} out", "", self.name, self.input, self.output,
+                 indent = indent)?;
+        if !self.rejected.is_empty() {
+            writeln!(f, "{:indent$}  rejected: {}", "", self.rejected.join(", "), indent = indent)?;
+        }
+        for c in self.children.iter() {
+            c.fmt_indented(f, indent + 2)?;
+        }
+        Ok(())
     }
 }
 
-impl<S:Selector> Selector for U64Sel<S> {
-    /// Select current `Json::U64` node if it is equal to `comp`
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::U64(..) => f(x),
-                _ => ()
-            }
-        })
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indented(f, 0)
     }
 }
 
-impl<S:Selector> Selector for U64Equals<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::U64(b) if b == self.comp => f(x),
-                _ => ()
-            }
-        })
-    }
+/// How a selector relates its input to its output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flow {
+    /// Each output is selected while handling a node of the inner
+    /// selector
+    Stream,
+    /// Outputs are selected from the combined results of the inner
+    /// selector
+    Aggregate
 }
 
-pub struct I64Sel<S> {
-    inner: S
+/// Record of a selector being traced
+struct Stage {
+    name: &'static str,
+    addr: usize,
+    flow: Flow,
+    calls: usize,
+    outputs: usize,
+    rejected: Vec<String>,
+    children: Vec<usize>
 }
 
-pub struct I64Equals<S> {
-    inner: S,
-    comp: i64
+/// Trace being recorded by the current query
+///
+/// Stages are identified by the address and type of the selector
+/// and by the stage which ran them.  `current` is the stage whose
+/// code is running, which changes as selectors call each other and
+/// the closures passed to them.  `next_addr`, set by
+/// `identify_stage`, replaces the address of the next selector.
+struct Tracer {
+    stages: Vec<Stage>,
+    roots: Vec<usize>,
+    current: Option<usize>,
+    next_addr: Option<usize>
 }
 
-impl<S:Selector> I64Sel<S> {
-    #[inline]
-    pub fn equals(self, comp: i64) -> I64Equals<S> {
-        let I64Sel { inner } = self;
-        I64Equals { inner: inner, comp: comp }
+impl Tracer {
+    fn new() -> Tracer {
+        Tracer { stages: Vec::new(), roots: Vec::new(), current: None, next_addr: None }
     }
-}
 
-impl<S:Selector> Selector for I64Sel<S> {
-    /// Select current `Json::I64` node if it is equal to `comp`
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::I64(..) => f(x),
-                _ => ()
+    fn enter(&mut self, name: &'static str, addr: usize, flow: Flow) -> (usize, Option<usize>) {
+        let addr = self.next_addr.take().unwrap_or(addr);
+        let caller = self.current;
+        let found = {
+            let siblings = match caller {
+                Some(c) => &self.stages[c].children,
+                None => &self.roots
+            };
+            siblings.iter().cloned().find(|&i| self.stages[i].addr == addr && self.stages[i].name == name)
+        };
+        let stage = match found {
+            Some(i) => i,
+            None => {
+                let i = self.stages.len();
+                self.stages.push(Stage {
+                    name, addr, flow, calls: 0, outputs: 0,
+                    rejected: Vec::new(), children: Vec::new()
+                });
+                match caller {
+                    Some(c) => self.stages[c].children.push(i),
+                    None => self.roots.push(i)
+                }
+                i
             }
-        })
+        };
+        self.stages[stage].calls += 1;
+        self.current = Some(stage);
+        (stage, caller)
     }
-}
 
-impl<S:Selector> Selector for I64Equals<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::I64(b) if b == self.comp => f(x),
-                _ => ()
-            }
-        })
+    fn reject(&mut self, stage: usize, path: String) {
+        let rejected = &mut self.stages[stage].rejected;
+        if rejected.len() < TRACE_SAMPLES && !rejected.contains(&path) {
+            rejected.push(path)
+        }
     }
-}
 
-pub struct F64Sel<S> {
-    inner: S
+    fn finish(&self, stage: usize) -> Trace {
+        let s = &self.stages[stage];
+        Trace {
+            name: s.name,
+            // A stage's first child is the selector before it in the chain
+            input: match s.children.first() {
+                Some(&c) => self.stages[c].outputs,
+                None => s.calls
+            },
+            output: s.outputs,
+            rejected: s.rejected.clone(),
+            children: s.children.iter().map(|&c| self.finish(c)).collect()
+        }
+    }
 }
 
-pub struct F64Equals<S> {
-    inner: S,
-    comp: f64
+/// Counters of a selector being profiled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectorProfile {
+    name: &'static str,
+    calls: usize,
+    nodes_visited: usize,
+    sets_created: usize,
+    set_insertions: usize,
+    time: Duration
 }
 
-impl<S:Selector> F64Sel<S> {
-    #[inline]
-    pub fn equals(self, comp: f64) -> F64Equals<S> {
-        let F64Sel { inner } = self;
-        F64Equals { inner: inner, comp: comp }
+impl SelectorProfile {
+    /// Return the name of the selector type
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// Return the number of times the selector was run
+    pub fn calls(&self) -> usize {
+        self.calls
+    }
+
+    /// Return the number of nodes the selector stepped into
+    pub fn nodes_visited(&self) -> usize {
+        self.nodes_visited
+    }
+
+    /// Return the number of sets of nodes the selector created to
+    /// remove duplicates
+    pub fn sets_created(&self) -> usize {
+        self.sets_created
+    }
+
+    /// Return the number of insertions into those sets
+    pub fn set_insertions(&self) -> usize {
+        self.set_insertions
+    }
+
+    /// Return the time spent in the selector itself, excluding the
+    /// selectors it ran and those it passed its results to
+    pub fn time(&self) -> Duration {
+        self.time
     }
 }
 
-impl<S:Selector> Selector for F64Sel<S> {
-    /// Select current `Json::F64` node if it is equal to `comp`
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::F64(..) => f(x),
-                _ => ()
-            }
-        })
+/// Profile of a query
+///
+/// Produced by `JsonExt::profile`, this holds a `SelectorProfile`
+/// for each type of selector the query ran, with the counters of
+/// all selectors of that type added together.  They are ordered by
+/// decreasing time.  Its `Display` implementation prints one line
+/// per selector type followed by the totals.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    selectors: Vec<SelectorProfile>
+}
+
+impl Profile {
+    /// Return the counters of each selector type
+    pub fn selectors(&self) -> &[SelectorProfile] {
+        &self.selectors
+    }
+
+    /// Return the counters of selectors of type `name`, if any ran
+    pub fn selector(&self, name: &str) -> Option<&SelectorProfile> {
+        self.selectors.iter().find(|s| s.name == name)
+    }
+
+    /// Return the total number of nodes visited
+    pub fn nodes_visited(&self) -> usize {
+        self.selectors.iter().map(|s| s.nodes_visited).sum()
+    }
+
+    /// Return the total number of sets created
+    pub fn sets_created(&self) -> usize {
+        self.selectors.iter().map(|s| s.sets_created).sum()
+    }
+
+    /// Return the total number of set insertions
+    pub fn set_insertions(&self) -> usize {
+        self.selectors.iter().map(|s| s.set_insertions).sum()
+    }
+
+    /// Return the total time spent in selectors
+    pub fn time(&self) -> Duration {
+        self.selectors.iter().map(|s| s.time).sum()
     }
 }
 
-impl<S:Selector> Selector for F64Equals<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::F64(b) if b == self.comp => f(x),
-                _ => ()
-            }
-        })
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line = |f: &mut fmt::Formatter, name: &str, calls: usize, nodes: usize,
+                        sets: usize, inserts: usize, time: Duration| {
+            writeln!(f, "{:<16} {:>8} {:>10} {:>8} {:>10} {:>12?}", name, calls, nodes, sets, inserts, time)
+        };
+        writeln!(f, "{:<16} {:>8} {:>10} {:>8} {:>10} {:>12}",
+                 "selector", "calls", "nodes", "sets", "inserts", "time")?;
+        for s in self.selectors.iter() {
+            line(f, s.name, s.calls, s.nodes_visited, s.sets_created, s.set_insertions, s.time)?;
+        }
+        line(f, "total", self.selectors.iter().map(|s| s.calls).sum(), self.nodes_visited(),
+             self.sets_created(), self.set_insertions(), self.time())
     }
 }
 
-pub struct NullSel<S> {
-    inner: S
+/// Profile being recorded by the current query
+///
+/// Time is charged to the selector in `current` whenever control
+/// passes between selectors, so each selector's time excludes that
+/// of the selectors it calls and of the closures it calls back.
+struct Profiler {
+    selectors: Vec<SelectorProfile>,
+    current: Option<usize>,
+    last: Instant
 }
 
-impl<S:Selector> Selector for NullSel<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::Null => f(x),
-                _ => ()
+impl Profiler {
+    fn new() -> Profiler {
+        Profiler { selectors: Vec::new(), current: None, last: Instant::now() }
+    }
+
+    fn enter(&mut self, name: &'static str) -> (usize, Option<usize>) {
+        let index = match self.selectors.iter().position(|s| s.name == name) {
+            Some(i) => i,
+            None => {
+                self.selectors.push(SelectorProfile {
+                    name, calls: 0, nodes_visited: 0, sets_created: 0,
+                    set_insertions: 0, time: Duration::new(0, 0)
+                });
+                self.selectors.len() - 1
             }
-        })
+        };
+        self.selectors[index].calls += 1;
+        let caller = self.current;
+        self.switch(Some(index));
+        (index, caller)
     }
-}
 
-pub struct At<S> {
-    inner: S,
-    index: usize
+    /// Charge the time since the last switch and make `to` current
+    fn switch(&mut self, to: Option<usize>) {
+        let now = Instant::now();
+        if let Some(c) = self.current {
+            self.selectors[c].time += now - self.last
+        }
+        self.last = now;
+        self.current = to;
+    }
+
+    fn finish(mut self) -> Profile {
+        self.switch(None);
+        self.selectors.sort_by_key(|s| Reverse(s.time));
+        Profile { selectors: self.selectors }
+    }
 }
 
-impl<S:Selector> Selector for At<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::Array(ref v) => {
-                    if v.len() > self.index {
-                        f(&x.descendant(&v[self.index]))
-                    }
-                }
-                _ => ()
-            }
-        })
+/// Run `f` on the current query context if it is instrumented
+#[inline]
+fn instrument<R,F:FnOnce(&mut Context) -> R>(f: F) -> Option<R> {
+    if MODE.with(Cell::get) != Mode::Instrumented {
+        return None
     }
+    with_current(None, |context| {
+        if context.tracer.is_some() || context.profiler.is_some() {
+            Some(f(context))
+        } else {
+            None
+        }
+    })
 }
 
-pub struct Key<'f,S> {
-    inner: S,
-    name: &'f str
+/// Identify the next selector run by `addr` instead of its own
+/// address, if tracing
+///
+/// Plans run selectors built on the fly, whose addresses change from
+/// one run to the next, so they identify them by their `Op` instead.
+fn identify_stage(addr: usize) {
+    instrument(|context| {
+        if let Some(ref mut t) = context.tracer {
+            t.next_addr = Some(addr)
+        }
+    });
 }
 
-impl<'f,S:Selector> Selector for Key<'f,S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::Object(ref m) => {
-                    match m.get(self.name) {
-                        Some(e) => f(&x.descendant(e)),
-                        _ => ()
-                    }
-                },
-                _ => ()
-            }
-        })
-    }
+/// Update the counters of the selector running, if profiling
+#[inline]
+fn tally<F:FnOnce(&mut SelectorProfile)>(f: F) {
+    instrument(|context| {
+        if let Some(Profiler { current: Some(i), ref mut selectors, .. }) = context.profiler {
+            f(&mut selectors[i])
+        }
+    });
 }
 
-pub struct Child<S> {
-    inner: S
+/// Instrumentation of a call to `Selector::select`
+struct Probe {
+    /// Stage in the trace and the stage which ran it, if tracing
+    stage: Option<(usize, Option<usize>)>,
+    /// Path the selector was run on and its outputs so far, kept
+    /// for aggregating selectors while tracing
+    input: Option<(String, usize)>,
+    /// Index of the selector in the profile and of the selector
+    /// which ran it, if profiling
+    timed: Option<(usize, Option<usize>)>
 }
 
-impl<S:Selector> Selector for Child<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            match x.node() {
-                &Json::Object(ref m) => {
-                    for (_,child) in m.iter() {
-                        f(&x.descendant(child))
-                    }
+impl Probe {
+    /// Start instrumenting a call to `select` of the selector named
+    /// `name` at `addr` on `input`
+    #[cold]
+    #[inline(never)]
+    fn start(name: &'static str, addr: usize,
+             input: &JsonPath, flow: Flow) -> Option<Box<Probe>> {
+        instrument(|context| Box::new(Probe::enter(context, name, addr, input, flow)))
+    }
+
+    fn enter(context: &mut Context, name: &'static str, addr: usize,
+             input: &JsonPath, flow: Flow) -> Probe {
+        let mut probe = Probe { stage: None, input: None, timed: None };
+        if let Some(ref mut t) = context.tracer {
+            let (stage, caller) = t.enter(name, addr, flow);
+            if flow == Flow::Aggregate {
+                probe.input = Some((input.to_string(), t.stages[stage].outputs))
+            }
+            probe.stage = Some((stage, caller))
+        }
+        probe.timed = context.profiler.as_mut().map(|p| p.enter(name));
+        probe
+    }
+
+    /// Account for the selector emitting a node, before passing it on
+    ///
+    /// Returns `None` if the query has been aborted and the node
+    /// should be dropped, otherwise what `after_emit` needs.
+    #[cold]
+    fn before_emit(&self) -> Option<Option<usize>> {
+        if aborted() {
+            return None
+        }
+        let before = instrument(|context| {
+            if let (Some(p), Some((_, caller))) = (context.profiler.as_mut(), self.timed) {
+                p.switch(caller)
+            }
+            match (context.tracer.as_mut(), self.stage) {
+                (Some(t), Some((stage, caller))) => {
+                    t.stages[stage].outputs += 1;
+                    t.current = caller;
+                    caller.map(|c| t.stages[c].outputs)
                 },
-                &Json::Array(ref v) => {
-                    for child in v.iter() {
-                        f(&x.descendant(child))
+                _ => None
+            }
+        });
+        Some(before.flatten())
+    }
+
+    /// Account for node `x` having been passed on, given what
+    /// `before_emit` returned
+    #[cold]
+    fn after_emit(&self, x: &JsonPath, before: Option<usize>) {
+        instrument(|context| {
+            if let (Some(p), Some((index, _))) = (context.profiler.as_mut(), self.timed) {
+                p.switch(Some(index))
+            }
+            match (context.tracer.as_mut(), self.stage, before) {
+                (Some(t), Some((stage, Some(c))), Some(n)) => {
+                    t.current = Some(stage);
+                    let handled = t.stages[c].flow == Flow::Stream &&
+                        t.stages[c].children.first() == Some(&stage);
+                    if handled && t.stages[c].outputs == n {
+                        t.reject(c, x.to_string())
                     }
                 },
+                (Some(t), Some((stage, _)), _) => t.current = Some(stage),
                 _ => ()
             }
-        })
+        });
     }
 }
 
-pub struct Parent<S> {
-    inner: S
-}
-
-impl<S:Selector> Selector for Parent<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        let mut seen = hash_set::HashSet::new();
-        self.inner.select(input, |x| {
-            match x.parent() {
-                Some(&p) => {
-                    let j = p.node();
-                    if !seen.contains(&(j as *const Json)) {
-                        seen.insert(j as *const Json);
-                        f(&p)
-                    }
+impl Drop for Probe {
+    fn drop(&mut self) {
+        if self.stage.is_none() && self.timed.is_none() {
+            return
+        }
+        let input = self.input.take();
+        instrument(|context| {
+            if let (Some(p), Some((_, caller))) = (context.profiler.as_mut(), self.timed) {
+                p.switch(caller)
+            }
+            if let (Some(t), Some((stage, caller))) = (context.tracer.as_mut(), self.stage) {
+                t.current = caller;
+                match input {
+                    Some((path, outputs)) if t.stages[stage].outputs == outputs =>
+                        t.reject(stage, path),
+                    _ => ()
                 }
-                _ => ()
             }
-        })
+        });
     }
 }
 
-pub struct Descend<S> {
-    inner: S
+/// Return the name of selector type `S` without its module path
+/// and type parameters
+fn selector_name<S>() -> &'static str {
+    let name = std::any::type_name::<S>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
 }
 
-fn descend_helper<'a,'b,F>(input: &JsonPath<'a,'b>,
-                           seen: &mut hash_set::HashSet<*const Json>,
-                           mut f: F)
-                           where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-    let j = input.node();
-    if !seen.contains(&(j as *const Json)) {
-        seen.insert(j as *const Json);
-        match j {
-            &Json::Object(ref m) => {
-                for (_,c) in m.iter() {
-                    let inner = input.descendant(c);
-                    f(&inner);
-                    descend_helper(&inner, seen, |x| f(x))
-                }
-            },
-            &Json::Array(ref v) => {
-                for c in v.iter() {
-                    let inner = input.descendant(c);
-                    f(&inner);
-                    descend_helper(&inner, seen, |x| f(x))
-                }
+/// Instrument a call to `select` of `sel` on `input`
+///
+/// Returns a closure wrapping `f` which `select` should call instead.
+/// When the query isn't instrumented this only forwards to `f`.
+fn probe<'a,'b,S,F>(sel: &S, input: &JsonPath<'a,'b>, flow: Flow, mut f: F)
+                    -> impl for<'c> FnMut(&JsonPath<'a,'c>)
+                    where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+    let mode = MODE.with(Cell::get);
+    let probe = match mode {
+        Mode::Instrumented => {
+            let addr = sel as *const S as usize;
+            Probe::start(selector_name::<S>(), addr, input, flow)
+        },
+        _ => None
+    };
+    move |x: &JsonPath<'a,'_>| {
+        let before = match probe {
+            Some(ref probe) => match probe.before_emit() {
+                Some(before) => before,
+                None => return
             },
-            _ => ()
+            None if mode == Mode::Limited && aborted() => return,
+            None => None
+        };
+        f(x);
+        if let Some(ref probe) = probe {
+            probe.after_emit(x, before)
         }
     }
 }
 
-impl<S:Selector> Selector for Descend<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        let mut seen = hash_set::HashSet::new();
-        self.inner.select(input, |x| {
-            descend_helper(x, &mut seen, |x| f(x))
-        })
-    }
-}
+/// Estimated memory of an entry of a set of nodes
+const SET_ENTRY_SIZE: usize = std::mem::size_of::<*const Json>();
 
-pub struct Ascend<S> {
-    inner: S
+/// Set of nodes compared by identity
+///
+/// Selectors use this to avoid selecting a node twice.  Creating a
+/// set and inserting into it are counted when profiling, and its
+/// entries count towards the maximum set memory of the query.
+struct NodeSet {
+    nodes: hash_set::HashSet<*const Json>
 }
 
-fn ascend_helper<'a,'b,F>(input: &JsonPath<'a,'b>,
-                          seen: &mut hash_set::HashSet<*const Json>,
-                          mut f: F)
-                          where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-    let mut current = *input;
-    loop {
-        match current.parent() {
-            Some(x) => {
-                let j = x.node();
-                if !seen.contains(&(j as *const Json)) {
-                    seen.insert(j as *const Json);
-                    f(x);
-                    current = *x;
-                } else {
-                    break;
-                }
-            },
-            _ => break
-        }
+impl Drop for NodeSet {
+    fn drop(&mut self) {
+        track_set_memory(-((self.nodes.len() * SET_ENTRY_SIZE) as isize))
     }
 }
 
-impl<S:Selector> Selector for Ascend<S> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        let mut seen = hash_set::HashSet::new();
-        self.inner.select(input, |n| {
-            ascend_helper(n, &mut seen, |x| f(x));
-        })
+impl NodeSet {
+    fn new() -> NodeSet {
+        tally(|c| c.sets_created += 1);
+        NodeSet { nodes: hash_set::HashSet::new() }
+    }
+
+    fn contains(&self, j: &Json) -> bool {
+        self.nodes.contains(&(j as *const Json))
+    }
+
+    /// Insert `j`, returning `true` if it was not present
+    fn insert(&mut self, j: &Json) -> bool {
+        tally(|c| c.set_insertions += 1);
+        let inserted = self.nodes.insert(j as *const Json);
+        if inserted {
+            track_set_memory(SET_ENTRY_SIZE as isize)
+        }
+        inserted
     }
 }
 
-pub struct Wherein<S,T> {
-    inner: S,
-    filter: T
+/// Values of the right operand of an expression
+///
+/// Expressions buffer these while evaluating their left operand,
+/// then pair each left value with every buffered value.  The buffer
+/// counts towards the maximum set memory of the query, and each pair
+/// towards its maximum number of nodes visited.
+struct Operands<'a> {
+    values: Vec<Cow<'a,Json>>
 }
 
-impl<S:Selector,T:Selector> Selector for Wherein<S,T> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        self.inner.select(input, |x| {
-            let mut matches = false;
-            self.filter.select(x, |_| matches = true);
-            if matches {
-                f(x)
-            }
-        })
+impl<'a> Drop for Operands<'a> {
+    fn drop(&mut self) {
+        track_set_memory(-((self.values.len() * SET_ENTRY_SIZE) as isize))
     }
 }
 
-pub struct Union<I,S,T> {
-    inner: I,
-    left: S,
-    right: T
-}
+impl<'a> Operands<'a> {
+    fn new() -> Operands<'a> {
+        Operands { values: Vec::new() }
+    }
 
-impl<I:Selector,S:Selector,T:Selector> Selector for Union<I,S,T> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+    fn push(&mut self, v: Cow<'a,Json>) {
+        if !aborted() {
+            track_set_memory(SET_ENTRY_SIZE as isize);
+            self.values.push(v)
+        }
+    }
+
+    /// Call `f` with each value, stopping if the query is aborted
+    fn for_each<F:FnMut(&Json)>(&self, mut f: F) {
+        for v in self.values.iter() {
+            if !visit_node() {
+                return
+            }
+            f(v)
+        }
+    }
+
+    /// Check whether `pred` holds for any value, stopping if the
+    /// query is aborted
+    fn any<P:FnMut(&Json) -> bool>(&self, mut pred: P) -> bool {
+        self.values.iter().any(|v| visit_node() && pred(v))
+    }
+}
+
+/// JSON selector trait
+///
+/// Implementors of this trait select nodes from `Json` objects
+/// according to some criteria.
+pub trait Selector: Sized {
+    /// Select matching nodes
+    ///
+    /// Given the path to a single node, `input`, this method should
+    /// identify nodes to be selected and invoke the closure `f` with
+    /// a path to each.
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>);
+
+    /// Select current node if it is a `Json::Boolean`
+    #[inline]
+    fn boolean(self) -> BooleanSel<Self> {
+        BooleanSel { inner: self }
+    }
+
+    /// Select current node if it is a `Json::U64`
+    #[inline]
+    fn uint64(self) -> U64Sel<Self> {
+        U64Sel { inner: self }
+    }
+
+    /// Select current node if it is a `Json::I64`
+    #[inline]
+    fn int64(self) -> I64Sel<Self> {
+        I64Sel { inner: self }
+    }
+
+    /// Select current node if it is a `Json::F64`
+    #[inline]
+    fn float64(self) -> F64Sel<Self> {
+        F64Sel { inner: self }
+    }
+
+    /// Select current node if it is a `Json::String`
+    #[inline]
+    fn string(self) -> StringSel<Self> {
+        StringSel { inner: self }
+    }
+
+    /// Select current node if it is a `Json::Object`
+    #[inline]
+    fn object(self) -> ObjectSel<Self> {
+        ObjectSel { inner: self }
+    }
+
+    /// Select current node if it is a `Json::Array`
+    #[inline]
+    fn list(self) -> ListSel<Self> {
+        ListSel { inner: self }
+    }
+
+    /// Select current node if it is a `Json::Null`
+    #[inline]
+    fn null(self) -> NullSel<Self> {
+        NullSel { inner: self }
+    }
+
+    /// Select list element
+    ///
+    /// If the current node is a `Json::Array` of at least `index + 1`
+    /// elements, selects the element at `index`.  Otherwise no nodes
+    /// are selected.
+    #[inline]
+    fn at(self, index: usize) -> At<Self> {
+        At { inner: self, index }
+    }
+
+    /// Select object value for key
+    ///
+    /// If the current node is a `Json::Object` that contains the key
+    /// `name`, its value is selected.  Otherwise no nodes are
+    /// selected.  `name` may be a borrowed `&str` or an owned
+    /// `String`, in which case the selector does not borrow from it.
+    #[inline]
+    fn key<'f,N:Into<Cow<'f,str>>>(self, name: N) -> Key<'f, Self> {
+        Key { inner: self, name: name.into() }
+    }
+
+    /// Select children of current node
+    ///
+    /// Selects all immediate child nodes of the current node: all
+    /// elements of a `Json::Array`, or all values of a `Json::Object`.
+    #[inline]
+    fn child(self) -> Child<Self> {
+        Child { inner: self }
+    }
+
+    /// Select parent of current node
+    ///
+    /// Selects the parent of the current node if it is not the root,
+    /// otherwise no nodes are selected.
+    #[inline]
+    fn parent(self) -> Parent<Self> {
+        Parent { inner: self }
+    }
+
+    /// Select descendents of current node
+    ///
+    /// Selects all child nodes of the current node and all their
+    /// children, recursively.  Nodes more than the maximum depth
+    /// given by the query `Limits` below the current node are not
+    /// selected.
+    #[inline]
+    fn descend(self) -> Descend<Self> {
+        Descend { inner: self }
+    }
+
+    /// Select descendents of current node breadth-first
+    ///
+    /// Selects the same nodes as `descend`, but in order of
+    /// increasing depth below the current node: all children first,
+    /// then all grandchildren, and so on.
+    #[inline]
+    fn descend_breadth(self) -> DescendBreadth<Self> {
+        DescendBreadth { inner: self }
+    }
+
+    /// Select descendents of current node up to a depth
+    ///
+    /// Like `descend`, but only selects nodes at most `depth` levels
+    /// below the current node: `descend_max(1)` is equivalent to
+    /// `child()`, and `descend_max(0)` selects nothing.
+    #[inline]
+    fn descend_max(self, depth: usize) -> DescendMax<Self> {
+        DescendMax { inner: self, depth }
+    }
+
+    /// Select current node and its descendents
+    ///
+    /// Like `descend`, but also selects the current node itself.
+    #[inline]
+    fn descend_or_self(self) -> DescendOrSelf<Self> {
+        DescendOrSelf { inner: self }
+    }
+
+    /// Select descendents of current node, stopping at matches
+    ///
+    /// Like `descend`, but nodes on which `filter` selects any nodes
+    /// are selected without selecting their own descendents.
+    #[inline]
+    fn descend_until<T:Selector>(self, filter: T) -> DescendUntil<Self,T> {
+        DescendUntil { inner: self, filter }
+    }
+
+    /// Select descendents of current node, pruning matches
+    ///
+    /// Like `descend`, but nodes on which `filter` selects any nodes
+    /// are skipped along with all their descendents.
+    #[inline]
+    fn descend_prune<T:Selector>(self, filter: T) -> DescendPrune<Self,T> {
+        DescendPrune { inner: self, filter }
+    }
+
+    /// Select ancestors of current node
+    ///
+    /// Selects the parent, grandparent, etc. of the current node up
+    /// to the root of the tree.
+    #[inline]
+    fn ascend(self) -> Ascend<Self> {
+        Ascend { inner: self }
+    }
+
+    /// Select root of the document
+    ///
+    /// Selects the root node of the document containing the current
+    /// node.  This allows filters given to `wherein` to refer to
+    /// data elsewhere in the document.
+    #[inline]
+    fn root(self) -> RootSel<Self> {
+        RootSel { inner: self }
+    }
+
+    /// Select next sibling of current node
+    ///
+    /// Selects the node following the current node in its parent:
+    /// the next element of a `Json::Array`, or the value for the
+    /// next key of a `Json::Object` in key order.  If the current
+    /// node is the last child or the root, no nodes are selected.
+    #[inline]
+    fn next_sibling(self) -> Sibling<Self> {
+        Sibling { inner: self, axis: Axis::Next }
+    }
+
+    /// Select previous sibling of current node
+    ///
+    /// Like `next_sibling`, but selects the node preceding the
+    /// current node in its parent.
+    #[inline]
+    fn prev_sibling(self) -> Sibling<Self> {
+        Sibling { inner: self, axis: Axis::Prev }
+    }
+
+    /// Select following siblings of current node
+    ///
+    /// Selects all nodes following the current node in its parent,
+    /// in order.
+    #[inline]
+    fn following_siblings(self) -> Sibling<Self> {
+        Sibling { inner: self, axis: Axis::Following }
+    }
+
+    /// Select preceding siblings of current node
+    ///
+    /// Selects all nodes preceding the current node in its parent,
+    /// in order.
+    #[inline]
+    fn preceding_siblings(self) -> Sibling<Self> {
+        Sibling { inner: self, axis: Axis::Preceding }
+    }
+
+    /// Select siblings of current node
+    ///
+    /// Selects all children of the parent of the current node other
+    /// than the current node itself, in order.
+    #[inline]
+    fn siblings(self) -> Sibling<Self> {
+        Sibling { inner: self, axis: Axis::All }
+    }
+
+    /// Select current node if it is the first element of a list
+    ///
+    /// Selects the current node if its parent is a `Json::Array` and
+    /// it is the element at index 0.
+    #[inline]
+    fn first(self) -> PositionSel<Self> {
+        PositionSel { inner: self, position: Position::First }
+    }
+
+    /// Select current node if it is the last element of a list
+    #[inline]
+    fn last(self) -> PositionSel<Self> {
+        PositionSel { inner: self, position: Position::Last }
+    }
+
+    /// Select current node if it is the list element at `index`
+    ///
+    /// This is the converse of `at`: `at(n)` selects the element at
+    /// index `n` of the current node, while `nth(n)` filters on the
+    /// index of the current node in its parent.
+    #[inline]
+    fn nth(self, index: usize) -> PositionSel<Self> {
+        PositionSel { inner: self, position: Position::Nth(index) }
+    }
+
+    /// Select current node if it is a list element at an even index
+    ///
+    /// Indices count from 0, so the first element is even.
+    #[inline]
+    fn even(self) -> PositionSel<Self> {
+        PositionSel { inner: self, position: Position::Even }
+    }
+
+    /// Select current node if it is a list element at an odd index
+    #[inline]
+    fn odd(self) -> PositionSel<Self> {
+        PositionSel { inner: self, position: Position::Odd }
+    }
+
+    /// Select current node if it is `depth` levels below the root
+    ///
+    /// The root itself is at depth 0, its children at depth 1, and
+    /// so on.  Nodes made up by selectors such as `and` aren't in the
+    /// document and are never selected.
+    #[inline]
+    fn at_depth(self, depth: usize) -> AtDepth<Self> {
+        AtDepth { inner: self, depth }
+    }
+
+    /// Select current node by its key or index in its parent
+    ///
+    /// Selects the current node if it is the value of an object key
+    /// matching the glob `pattern`, or a list element whose index
+    /// written in decimal matches `pattern`.  In the pattern, `*`
+    /// matches any sequence of characters and `?` matches any single
+    /// character.  For example, `descend().named("password")`
+    /// selects all values of `"password"` keys at any depth.
+    #[inline]
+    fn named<'f,P:Into<Cow<'f,str>>>(self, pattern: P) -> Named<'f, Self> {
+        Named { inner: self, pattern: pattern.into() }
+    }
+
+    /// Select current node if it has no children
+    ///
+    /// Selects the current node if it is a scalar or an empty
+    /// `Json::Array` or `Json::Object`.
+    #[inline]
+    fn leaf(self) -> Leaf<Self> {
+        Leaf { inner: self }
+    }
+
+    /// Select current node based on filter
+    ///
+    /// Runs the selector `filter` on the current node.  If it selects
+    /// any nodes, the current node is selected.  If it does not select
+    /// any nodes, no nodes are selected.
+    #[inline]
+    fn wherein<T:Selector>(self, filter: T) -> Wherein<Self,T> {
+        Wherein { inner: self, filter }
+    }
+
+    /// Select current node based on comparison
+    ///
+    /// Evaluates the expressions `left` and `right` on the current
+    /// node and selects the current node if some value of `left`
+    /// compares to some value of `right` according to `op`.  Any
+    /// selector may be used as an expression, in which case its
+    /// values are the nodes it selects.  For example,
+    /// `compare(key("used"), Cmp::Gt, key("limit"))` selects objects
+    /// where the value for `"used"` is greater than that for
+    /// `"limit"`.
+    ///
+    /// Numbers compare exactly by numeric value regardless of
+    /// whether they are `Json::U64`, `Json::I64` or `Json::F64`, so
+    /// integers beyond the precision of a float are told apart from
+    /// it and from each other.  Strings compare
+    /// lexicographically, and `false` is less than `true`.  Lists
+    /// and objects are equal only if they are deeply equal as for
+    /// `equals_json`, and values of different kinds are never
+    /// ordered.
+    #[inline]
+    fn compare<T1:Expr,T2:Expr>(self, left: T1, op: Cmp, right: T2) -> Compare<Self,T1,T2> {
+        Compare { inner: self, left, op, right }
+    }
+
+    /// Select current node if it is equal to `value`
+    ///
+    /// Selects the current node if it is deeply equal to `value`,
+    /// which may be a borrowed `&Json` or an owned `Json`.  Numbers
+    /// are compared by numeric value, so `1` and `1.0` are equal,
+    /// while other values must be of the same kind.
+    #[inline]
+    fn equals_json<'j,J:IntoJson<'j>>(self, value: J) -> EqualsJson<'j, Self> {
+        EqualsJson { inner: self, value: value.into_json() }
+    }
+
+    /// Select current node if it contains `template`
+    ///
+    /// Selects the current node if it matches `template` as a
+    /// pattern: an object contains a template object if it has all
+    /// its keys and the value for each contains the corresponding
+    /// value of the template, a list contains a template list if
+    /// each element of the template is contained by some element
+    /// of the list, and other values contain a template if they are
+    /// equal to it as for `equals_json`.
+    #[inline]
+    fn contains_json<'j,J:IntoJson<'j>>(self, template: J) -> ContainsJson<'j, Self> {
+        ContainsJson { inner: self, template: template.into_json() }
+    }
+
+    /// Select current node if two selectors select equal values
+    ///
+    /// Shorthand for `compare(left, Cmp::Eq, right)`.
+    #[inline]
+    fn equals_sel<T1:Expr,T2:Expr>(self, left: T1, right: T2) -> Compare<Self,T1,T2> {
+        self.compare(left, Cmp::Eq, right)
+    }
+
+    /// Map expression over selected nodes
+    ///
+    /// Creates an expression whose values are those of `expr`
+    /// evaluated on each node selected by the current selector.
+    /// Use `JsonExt::eval` to collect them.
+    #[inline]
+    fn map<E:Expr>(self, expr: E) -> Map<Self,E> {
+        Map { inner: self, expr }
+    }
+
+    /// Select current node based on predicate
+    ///
+    /// Selects the current node if the closure `pred` returns `true`
+    /// when given the node.  This allows arbitrary conditions to be
+    /// expressed without implementing `Selector`.
+    #[inline]
+    fn filter<P>(self, pred: P) -> Filter<Self,P>
+                 where P: Fn(&Json) -> bool {
+        Filter { inner: self, pred }
+    }
+
+    /// Select current node based on path predicate
+    ///
+    /// Like `filter`, but the closure `pred` is given the path to the
+    /// current node, so it may also inspect its location and its
+    /// ancestors.
+    #[inline]
+    fn filter_path<P>(self, pred: P) -> FilterPath<Self,P>
+                      where P: for<'a,'b> Fn(&JsonPath<'a,'b>) -> bool {
+        FilterPath { inner: self, pred }
+    }
+
+    /// Select nodes found by closure
+    ///
+    /// Calls the closure `f` with the current node and selects each
+    /// node in the vector it returns.  The returned nodes must be
+    /// the current node or its descendants, which makes `flat_map`
+    /// a simple way to write custom navigation: for example,
+    /// `flat_map(|j| j.search("id").into_iter().collect())` selects
+    /// the first `"id"` value found below the current node.  Other
+    /// returned nodes are ignored.  Finding the paths of nodes below
+    /// the children of the current node visits every node below it,
+    /// once per call of `f`.
+    #[inline]
+    fn flat_map<M>(self, f: M) -> FlatMap<Self,M>
+                   where M: for<'a> Fn(&'a Json) -> Vec<&'a Json> {
+        FlatMap { inner: self, f }
+    }
+
+    /// Select union of two selectors
+    ///
+    /// Runs `left` and `right` on the current node and selects
+    /// nodes which are selected by either.
+    #[inline]
+    fn union<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> Union<Self,T1,T2> {
+        Union { inner: self, left, right }
+    }
+
+    /// Select intersection of two selectors
+    ///
+    /// Runs `left` and `right` on the current node and selects
+    /// nodes which are selected by both.
+    #[inline]
+    fn intersect<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> Intersect<Self,T1,T2> {
+        Intersect { inner: self, left, right }
+    }
+
+    /// Select symmetric difference of two selectors
+    ///
+    /// Runs `left` and `right` on the current node, selecting
+    /// nodes which are selected by `left` but not selected
+    /// by `right`.
+    ///
+    /// Warning: this selector will execute its parent in the chain
+    /// twice which may result in bad performance.
+    #[inline]
+    fn diff<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> Diff<Self,T1,T2> {
+        Diff { inner: self, left, right }
+    }
+
+    /// Select logical-and of two selectors
+    ///
+    /// Runs `left` and `right` on the current node and
+    /// selects an arbitrary node if both selected at
+    /// least one node themselves.  This is useful for
+    /// encoding logical-and conditions for `wherein`.
+    #[inline]
+    fn and<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> AndSel<Self,T1,T2> {
+        AndSel { inner: self, left, right }
+    }
+
+    /// Select logical-or of two selectors
+    ///
+    /// Runs `left` and `right` on the current node and
+    /// selects an arbitrary node if either selected at
+    /// least one node themselves.  This is useful for
+    /// encoding logical-and conditions for `wherein`.
+    #[inline]
+    fn or<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> OrSel<Self,T1,T2> {
+        OrSel { inner: self, left, right }
+    }
+
+    /// Select union of any number of selectors
+    ///
+    /// Like `union`, but runs each selector in `list` on the current
+    /// node, selecting nodes which are selected by any of them.
+    /// `list` may be a tuple, array, slice or vector of selectors.
+    #[inline]
+    fn union_all<L:Selectors>(self, list: L) -> UnionAll<Self,L> {
+        UnionAll { inner: self, list }
+    }
+
+    /// Select logical-or of any number of selectors
+    ///
+    /// Like `or`, but selects an arbitrary node if any selector in
+    /// `list` selected at least one node.  Selectors after the first
+    /// one to select a node are not run.
+    #[inline]
+    fn any_of<L:Selectors>(self, list: L) -> AnyOf<Self,L> {
+        AnyOf { inner: self, list }
+    }
+
+    /// Select logical-and of any number of selectors
+    ///
+    /// Like `and`, but selects an arbitrary node if every selector in
+    /// `list` selected at least one node.
+    #[inline]
+    fn all_of<L:Selectors>(self, list: L) -> AllOf<Self,L> {
+        AllOf { inner: self, list }
+    }
+
+    /// Select conditionally
+    ///
+    /// Runs `cond` on the current node.  If it selects any nodes,
+    /// selects the nodes selected by `then` from the current node,
+    /// otherwise those selected by `otherwise`.
+    #[inline]
+    fn if_then_else<C:Selector,T:Selector,E:Selector>(self, cond: C, then: T, otherwise: E)
+                                                     -> IfThenElse<Self,C,T,E> {
+        IfThenElse { inner: self, cond, then, otherwise }
+    }
+
+    /// Select first non-empty alternative
+    ///
+    /// Runs each selector in `list` on the current node in order,
+    /// selecting the nodes selected by the first one which selects
+    /// any nodes.  The remaining selectors are not run.  `list` may
+    /// be a tuple, array, slice or vector of selectors.  For
+    /// example, `coalesce((key("hostname"), key("host")))` selects
+    /// the value for `"hostname"` if present, otherwise the value
+    /// for `"host"`.
+    #[inline]
+    fn coalesce<L:Selectors>(self, list: L) -> Coalesce<Self,L> {
+        Coalesce { inner: self, list }
+    }
+
+    /// Select distinct values
+    ///
+    /// Selects the nodes selected by the current selector, skipping
+    /// any node whose value is equal to one already selected.  The
+    /// first node selected with a given value is kept.  Values are
+    /// compared as by `equals_json`, so `1` and `1.0` are equal.
+    #[inline]
+    fn distinct(self) -> Distinct<Self> {
+        Distinct { inner: self }
+    }
+
+    /// Select union of two selectors by value
+    ///
+    /// Like `union`, but nodes are considered the same if their
+    /// values are equal rather than if they are the same node in
+    /// the document.  Each distinct value is selected once.
+    #[inline]
+    fn union_values<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> UnionValues<Self,T1,T2> {
+        UnionValues { inner: self, left, right }
+    }
+
+    /// Select intersection of two selectors by value
+    ///
+    /// Like `intersect`, but selects nodes selected by `left` or
+    /// `right` whose value is equal to a node selected by the other.
+    /// Each distinct value is selected once.
+    #[inline]
+    fn intersect_values<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> IntersectValues<Self,T1,T2> {
+        IntersectValues { inner: self, left, right }
+    }
+
+    /// Select difference of two selectors by value
+    ///
+    /// Like `diff`, but selects nodes selected by `left` whose value
+    /// is not equal to any node selected by `right`.  Each distinct
+    /// value is selected once.
+    ///
+    /// Warning: this selector will execute its parent in the chain
+    /// twice which may result in bad performance.
+    #[inline]
+    fn diff_values<T1:Selector,T2:Selector>(self, left: T1, right: T2) -> DiffValues<Self,T1,T2> {
+        DiffValues { inner: self, left, right }
+    }
+
+    /// Write the selector as the receiver of a method call
+    ///
+    /// Writes the selector followed by `.`.  Selectors equivalent to
+    /// `node()` write nothing if the method has a shorthand function,
+    /// so that `node().child()` is written `child()`.  Used by the
+    /// `Display` implementations of selectors; not meant to be
+    /// called or overridden elsewhere.
+    #[doc(hidden)]
+    #[inline]
+    fn fmt_inner(&self, f: &mut fmt::Formatter, _shorthand: bool) -> fmt::Result where Self: fmt::Display {
+        write!(f, "{}.", self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Node {
+    _dummy: ()
+}
+
+impl Selector for Node {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        f(input)
+    }
+
+    fn fmt_inner(&self, f: &mut fmt::Formatter, shorthand: bool) -> fmt::Result {
+        if shorthand {
+            Ok(())
+        } else {
+            f.write_str("node().")
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("node()")
+    }
+}
+
+/// Write selector `inner` followed by the method `call` on it
+///
+/// A chain starting at `node()` is written without it, as with the
+/// shorthand functions: `node().child()` is written `child()`.
+fn fmt_chain<S:Selector+fmt::Display>(f: &mut fmt::Formatter, inner: &S, call: fmt::Arguments) -> fmt::Result {
+    inner.fmt_inner(f, true)?;
+    f.write_fmt(call)
+}
+
+/// Write selector `inner` followed by the method `call` on it, which
+/// has no shorthand function
+fn fmt_call<S:Selector+fmt::Display>(f: &mut fmt::Formatter, inner: &S, call: fmt::Arguments) -> fmt::Result {
+    inner.fmt_inner(f, false)?;
+    f.write_fmt(call)
+}
+
+/// Adapter writing a float as a Rust expression
+struct F64Expr(f64);
+
+impl fmt::Display for F64Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            v if v.is_nan() => f.write_str("f64::NAN"),
+            f64::INFINITY => f.write_str("f64::INFINITY"),
+            f64::NEG_INFINITY => f.write_str("f64::NEG_INFINITY"),
+            v => write!(f, "{:?}", v)
+        }
+    }
+}
+
+/// Adapter writing a `Json` value as a Rust expression building it
+///
+/// Scalars are written as their variant and containers as
+/// `Json::from_str` on a raw string of their JSON text.
+struct JsonExpr<'j>(&'j Json);
+
+impl<'j> fmt::Display for JsonExpr<'j> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            Json::Null => f.write_str("Json::Null"),
+            Json::Boolean(b) => write!(f, "Json::Boolean({})", b),
+            Json::U64(v) => write!(f, "Json::U64({})", v),
+            Json::I64(v) => write!(f, "Json::I64({})", v),
+            Json::F64(v) => write!(f, "Json::F64({})", F64Expr(v)),
+            Json::String(ref s) => write!(f, "Json::String({:?}.to_string())", s),
+            ref j => {
+                let text = j.to_string();
+                // Enough hashes that the text can't end the raw string
+                let mut hashes = "#".to_string();
+                while text.contains(&format!("\"{}", hashes)) {
+                    hashes.push('#');
+                }
+                write!(f, "Json::from_str(r{}\"{}\"{}).unwrap()", hashes, text, hashes)
+            }
+        }
+    }
+}
+
+/// Implement the length predicates of a selector of `Json::$kind`
+/// nodes, whose lengths count `$units`
+macro_rules! len_predicates {
+    ($sel:ident, $kind:expr, $units:expr) => {
+        impl<S:Selector> $sel<S> {
+            #[doc = concat!("Select current `Json::", $kind, "` node if its length is `len`\n\n",
+                            "The length is the number of ", $units, ".")]
+            #[inline]
+            pub fn len_eq(self, len: usize) -> LenRange<Self> {
+                LenRange { inner: self, min: len, max: len }
+            }
+
+            #[doc = concat!("Select current `Json::", $kind, "` node if its length is greater than `len`")]
+            #[inline]
+            pub fn len_gt(self, len: usize) -> LenRange<Self> {
+                LenRange { inner: self, min: len.saturating_add(1), max: usize::MAX }
+            }
+
+            #[doc = concat!("Select current `Json::", $kind, "` node if its length is between `min`\n",
+                            "and `max` inclusive")]
+            #[inline]
+            pub fn len_between(self, min: usize, max: usize) -> LenRange<Self> {
+                LenRange { inner: self, min, max }
+            }
+
+            #[doc = concat!("Select current `Json::", $kind, "` node if it is empty")]
+            #[inline]
+            pub fn is_empty(self) -> LenRange<Self> {
+                self.len_eq(0)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectSel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for ObjectSel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("object()"))
+    }
+}
+
+len_predicates!(ObjectSel, "Object", "entries");
+
+impl<S:Selector> Selector for ObjectSel<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if let &Json::Object(..) = x.node() {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListSel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for ListSel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("list()"))
+    }
+}
+
+len_predicates!(ListSel, "Array", "elements");
+
+impl<S:Selector> Selector for ListSel<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if let &Json::Array(..) = x.node() {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StringSel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for StringSel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("string()"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StringEquals<'a,S> {
+    inner: S,
+    comp: Cow<'a,str>
+}
+
+impl<'s,S:Selector+fmt::Display> fmt::Display for StringEquals<'s,S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("string().equals({:?})", self.comp))
+    }
+}
+
+impl<S:Selector> StringSel<S> {
+    /// Select current `Json::String` node if it is equal to `comp`
+    #[inline]
+    pub fn equals<'a,C:Into<Cow<'a,str>>>(self, comp: C) -> StringEquals<'a, S> {
+        let StringSel { inner } = self;
+        StringEquals { inner, comp: comp.into() }
+    }
+}
+
+len_predicates!(StringSel, "String", "characters");
+
+impl<S:Selector> Selector for StringSel<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if let &Json::String(..) = x.node() {
+                f(x)
+            }
+        })
+    }
+}
+
+impl<'s,S:Selector> Selector for StringEquals<'s,S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.node() {
+                Json::String(s) if *self.comp == *s => f(x),
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LenRange<S> {
+    inner: S,
+    min: usize,
+    max: usize
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for LenRange<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.min == self.max {
+            fmt_call(f, &self.inner, format_args!("len_eq({})", self.min))
+        } else if self.min > 0 && self.max == usize::MAX {
+            fmt_call(f, &self.inner, format_args!("len_gt({})", self.min - 1))
+        } else {
+            fmt_call(f, &self.inner, format_args!("len_between({}, {})", self.min, self.max))
+        }
+    }
+}
+
+/// Length of a list, object or string, counting characters of the
+/// latter
+fn json_len(j: &Json) -> Option<usize> {
+    match j {
+        Json::Array(v) => Some(v.len()),
+        Json::Object(m) => Some(m.len()),
+        Json::String(s) => Some(s.chars().count()),
+        _ => None
+    }
+}
+
+impl<S:Selector> Selector for LenRange<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match json_len(x.node()) {
+                Some(len) if len >= self.min && len <= self.max => f(x),
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BooleanSel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for BooleanSel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("boolean()"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BooleanEquals<S> {
+    inner: S,
+    comp: bool
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for BooleanEquals<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("boolean().equals({})", self.comp))
+    }
+}
+
+impl<S:Selector> BooleanSel<S> {
+    /// Select current `Json::Boolean` node if it is equal to `comp`
+    #[inline]
+    pub fn equals(self, comp: bool) -> BooleanEquals<S> {
+        let BooleanSel { inner } = self;
+        BooleanEquals { inner, comp }
+    }
+}
+
+impl<S:Selector> Selector for BooleanSel<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if let &Json::Boolean(..) = x.node() {
+                f(x)
+            }
+        })
+    }
+}
+
+impl<S:Selector> Selector for BooleanEquals<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.node() {
+                &Json::Boolean(b) if b == self.comp => f(x),
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct U64Sel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for U64Sel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("uint64()"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct U64Equals<S> {
+    inner: S,
+    comp: u64
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for U64Equals<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("uint64().equals({})", self.comp))
+    }
+}
+
+impl<S:Selector> U64Sel<S> {
+    #[inline]
+    pub fn equals(self, comp: u64) -> U64Equals<S> {
+        let U64Sel { inner } = self;
+        U64Equals { inner, comp }
+    }
+}
+
+impl<S:Selector> Selector for U64Sel<S> {
+    /// Select current `Json::U64` node if it is equal to `comp`
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if let &Json::U64(..) = x.node() {
+                f(x)
+            }
+        })
+    }
+}
+
+impl<S:Selector> Selector for U64Equals<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.node() {
+                &Json::U64(b) if b == self.comp => f(x),
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct I64Sel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for I64Sel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("int64()"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct I64Equals<S> {
+    inner: S,
+    comp: i64
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for I64Equals<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("int64().equals({})", self.comp))
+    }
+}
+
+impl<S:Selector> I64Sel<S> {
+    #[inline]
+    pub fn equals(self, comp: i64) -> I64Equals<S> {
+        let I64Sel { inner } = self;
+        I64Equals { inner, comp }
+    }
+}
+
+impl<S:Selector> Selector for I64Sel<S> {
+    /// Select current `Json::I64` node if it is equal to `comp`
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if let &Json::I64(..) = x.node() {
+                f(x)
+            }
+        })
+    }
+}
+
+impl<S:Selector> Selector for I64Equals<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.node() {
+                &Json::I64(b) if b == self.comp => f(x),
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct F64Sel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for F64Sel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("float64()"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct F64Equals<S> {
+    inner: S,
+    comp: f64
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for F64Equals<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("float64().equals({})", F64Expr(self.comp)))
+    }
+}
+
+impl<S:Selector> F64Sel<S> {
+    /// Select current `Json::F64` node if it is equal to `comp`
+    ///
+    /// Values are compared with `==`, so `0.0` and `-0.0` are equal
+    /// and NaN is not equal to anything, including itself.  Results
+    /// of arithmetic rarely compare exactly equal, so `approx_equals`
+    /// or `ulps_equals` is usually a better choice for them.
+    #[inline]
+    pub fn equals(self, comp: f64) -> F64Equals<S> {
+        let F64Sel { inner } = self;
+        F64Equals { inner, comp }
+    }
+
+    /// Select current `Json::F64` node if it is approximately `comp`
+    ///
+    /// Selects the node if it differs from `comp` by at most
+    /// `abs_eps`, or by at most `rel_eps` times the larger magnitude
+    /// of the two.  Infinities only match themselves, and NaN never
+    /// matches.
+    #[inline]
+    pub fn approx_equals(self, comp: f64, abs_eps: f64, rel_eps: f64) -> F64Pred<Self> {
+        F64Pred { inner: self, test: F64Test::Approx(comp, abs_eps, rel_eps) }
+    }
+
+    /// Select current `Json::F64` node if it is within `max_ulps`
+    /// units in the last place of `comp`
+    ///
+    /// That is, there are at most `max_ulps - 1` representable values
+    /// between the two.  `0.0` and `-0.0` match each other, values
+    /// of opposite sign otherwise never match, and NaN never matches.
+    #[inline]
+    pub fn ulps_equals(self, comp: f64, max_ulps: u64) -> F64Pred<Self> {
+        F64Pred { inner: self, test: F64Test::Ulps(comp, max_ulps) }
+    }
+
+    /// Select current `Json::F64` node if it is NaN
+    #[inline]
+    pub fn is_nan(self) -> F64Pred<Self> {
+        F64Pred { inner: self, test: F64Test::Nan }
+    }
+
+    /// Select current `Json::F64` node if it is neither infinite nor NaN
+    #[inline]
+    pub fn is_finite(self) -> F64Pred<Self> {
+        F64Pred { inner: self, test: F64Test::Finite }
+    }
+
+    /// Select current `Json::F64` node if it is a whole number
+    #[inline]
+    pub fn is_integer(self) -> F64Pred<Self> {
+        F64Pred { inner: self, test: F64Test::Integer }
+    }
+}
+
+impl<S:Selector> Selector for F64Sel<S> {
+    /// Select current `Json::F64` node if it is equal to `comp`
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if let &Json::F64(..) = x.node() {
+                f(x)
+            }
+        })
+    }
+}
+
+impl<S:Selector> Selector for F64Equals<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.node() {
+                &Json::F64(b) if b == self.comp => f(x),
+                _ => ()
+            }
+        })
+    }
+}
+
+/// Test applied by `F64Pred`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum F64Test {
+    /// `approx_equals(comp, abs_eps, rel_eps)`
+    Approx(f64, f64, f64),
+    /// `ulps_equals(comp, max_ulps)`
+    Ulps(f64, u64),
+    /// `is_nan()`
+    Nan,
+    /// `is_finite()`
+    Finite,
+    /// `is_integer()`
+    Integer
+}
+
+impl fmt::Display for F64Test {
+    /// Write the method call creating the test
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            F64Test::Approx(comp, abs_eps, rel_eps) =>
+                write!(f, "approx_equals({}, {}, {})", F64Expr(comp), F64Expr(abs_eps), F64Expr(rel_eps)),
+            F64Test::Ulps(comp, max_ulps) => write!(f, "ulps_equals({}, {})", F64Expr(comp), max_ulps),
+            F64Test::Nan => f.write_str("is_nan()"),
+            F64Test::Finite => f.write_str("is_finite()"),
+            F64Test::Integer => f.write_str("is_integer()")
+        }
+    }
+}
+
+impl F64Test {
+    /// Check whether `v` passes the test
+    pub fn test(self, v: f64) -> bool {
+        match self {
+            F64Test::Approx(comp, abs_eps, rel_eps) => {
+                if v == comp {
+                    return true
+                }
+                if !v.is_finite() || !comp.is_finite() {
+                    return false
+                }
+                let diff = (v - comp).abs();
+                diff <= abs_eps || diff <= rel_eps * v.abs().max(comp.abs())
+            },
+            F64Test::Ulps(comp, max_ulps) => {
+                if v == comp {
+                    return true
+                }
+                if v.is_nan() || comp.is_nan() || v.is_sign_negative() != comp.is_sign_negative() {
+                    return false
+                }
+                // Bit patterns of floats of the same sign are ordered
+                // like the floats themselves
+                (v.to_bits() as i64 - comp.to_bits() as i64).unsigned_abs() <= max_ulps
+            },
+            F64Test::Nan => v.is_nan(),
+            F64Test::Finite => v.is_finite(),
+            F64Test::Integer => v.is_finite() && v.fract() == 0.0
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct F64Pred<S> {
+    inner: S,
+    test: F64Test
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for F64Pred<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_call(f, &self.inner, format_args!("{}", self.test))
+    }
+}
+
+impl<S:Selector> Selector for F64Pred<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.node() {
+                &Json::F64(v) if self.test.test(v) => f(x),
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NullSel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for NullSel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("null()"))
+    }
+}
+
+impl<S:Selector> Selector for NullSel<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if x.node() == &Json::Null { f(x) }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct At<S> {
+    inner: S,
+    index: usize
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for At<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("at({})", self.index))
+    }
+}
+
+impl<S:Selector> Selector for At<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.node() {
+                Json::Array(v)
+                    if v.len() > self.index => {
+                        f(&x.descendant(&v[self.index], Step::Index(self.index)))
+                    }
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Key<'f,S> {
+    inner: S,
+    name: Cow<'f,str>
+}
+
+impl<'f,S:Selector+fmt::Display> fmt::Display for Key<'f,S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("key({:?})", self.name))
+    }
+}
+
+impl<'f,S:Selector> Selector for Key<'f,S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if let Json::Object(m) = x.node() {
+                if let Some((k, e)) = m.get_key_value(&*self.name) {
+                    f(&x.descendant(e, Step::Key(k)))
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Child<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for Child<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("child()"))
+    }
+}
+
+impl<S:Selector> Selector for Child<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.node() {
+                Json::Object(m) => {
+                    for (k,child) in m.iter() {
+                        f(&x.descendant(child, Step::Key(k)))
+                    }
+                },
+                Json::Array(v) => {
+                    for (i,child) in v.iter().enumerate() {
+                        f(&x.descendant(child, Step::Index(i)))
+                    }
+                },
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parent<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for Parent<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("parent()"))
+    }
+}
+
+impl<S:Selector> Selector for Parent<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            if let Some(p) = x.parent() {
+                let j = p.node();
+                if !seen.contains(j) {
+                    seen.insert(j);
+                    f(&p)
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RootSel<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for RootSel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("root()"))
+    }
+}
+
+impl<S:Selector> Selector for RootSel<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            let mut current = *x;
+            while let Some(p) = current.parent() {
+                current = p
+            }
+            if seen.insert(current.node()) {
+                f(&current)
+            }
+        })
+    }
+}
+
+/// Sibling axis
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Axis {
+    /// `next_sibling()`
+    Next,
+    /// `prev_sibling()`
+    Prev,
+    /// `following_siblings()`
+    Following,
+    /// `preceding_siblings()`
+    Preceding,
+    /// `siblings()`
+    All
+}
+
+impl fmt::Display for Axis {
+    /// Write the method call selecting siblings on the axis
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Axis::Next => "next_sibling()",
+            Axis::Prev => "prev_sibling()",
+            Axis::Following => "following_siblings()",
+            Axis::Preceding => "preceding_siblings()",
+            Axis::All => "siblings()"
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sibling<S> {
+    inner: S,
+    axis: Axis
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for Sibling<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("{}", self.axis))
+    }
+}
+
+fn sibling_helper<'a,'b,F>(input: &JsonPath<'a,'b>, axis: Axis, mut f: F)
+                           where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+    let parent = match input.parent() {
+        Some(p) => p,
+        None => return
+    };
+    // The location is checked against the parent since synthetic
+    // nodes such as the result of `and` aren't really found there
+    match (parent.node(), input.step()) {
+        (Json::Array(v), Some(Step::Index(i)))
+            if i < v.len() && ptr::eq(&v[i], input.node()) => {
+            let range = match axis {
+                Axis::Next => i + 1 .. (i + 2).min(v.len()),
+                Axis::Prev => i.saturating_sub(1) .. i,
+                Axis::Following => i + 1 .. v.len(),
+                Axis::Preceding => 0 .. i,
+                Axis::All => 0 .. v.len()
+            };
+            for k in range {
+                if k != i {
+                    f(&parent.descendant(&v[k], Step::Index(k)))
+                }
+            }
+        },
+        (Json::Object(m), Some(Step::Key(name)))
+            if m.get(name).is_some_and(|c| ptr::eq(c, input.node())) => {
+            let mut emit = |k: &'a String, c: &'a Json| {
+                f(&parent.descendant(c, Step::Key(k)))
+            };
+            let following = m.range::<str,_>((Excluded(name), Unbounded));
+            let preceding = m.range::<str,_>((Unbounded, Excluded(name)));
+            match axis {
+                Axis::Next => following.take(1).for_each(|(k, c)| emit(k, c)),
+                Axis::Prev => preceding.rev().take(1).for_each(|(k, c)| emit(k, c)),
+                Axis::Following => following.for_each(|(k, c)| emit(k, c)),
+                Axis::Preceding => preceding.for_each(|(k, c)| emit(k, c)),
+                Axis::All => preceding.chain(following).for_each(|(k, c)| emit(k, c))
+            }
+        },
+        _ => ()
+    }
+}
+
+impl<S:Selector> Selector for Sibling<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            sibling_helper(x, self.axis, |x| {
+                if seen.insert(x.node()) {
+                    f(x)
+                }
+            })
+        })
+    }
+}
+
+/// Position of a list element
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Position {
+    /// `first()`
+    First,
+    /// `last()`
+    Last,
+    /// `nth(index)`
+    Nth(usize),
+    /// `even()`
+    Even,
+    /// `odd()`
+    Odd
+}
+
+impl Position {
+    /// Check whether index `i` in a list of length `len` is at the
+    /// position
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn test(self, i: usize, len: usize) -> bool {
+        match self {
+            Position::First => i == 0,
+            Position::Last => i + 1 == len,
+            Position::Nth(n) => i == n,
+            Position::Even => i % 2 == 0,
+            Position::Odd => i % 2 == 1
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    /// Write the method call selecting the position
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Position::First => f.write_str("first()"),
+            Position::Last => f.write_str("last()"),
+            Position::Nth(n) => write!(f, "nth({})", n),
+            Position::Even => f.write_str("even()"),
+            Position::Odd => f.write_str("odd()")
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionSel<S> {
+    inner: S,
+    position: Position
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for PositionSel<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("{}", self.position))
+    }
+}
+
+impl<S:Selector> Selector for PositionSel<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            match x.position() {
+                Some((i, len)) if self.position.test(i, len) => f(x),
+                _ => ()
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AtDepth<S> {
+    inner: S,
+    depth: usize
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for AtDepth<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("at_depth({})", self.depth))
+    }
+}
+
+impl<S:Selector> Selector for AtDepth<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if x.depth() == self.depth && x.step() != Some(Step::Synthetic) {
+                f(x)
+            }
+        })
+    }
+}
+
+/// Match `name` against glob `pattern`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` in the pattern and the position in
+    // the name it was tried at, for backtracking
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else {
+            match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                },
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Named<'f,S> {
+    inner: S,
+    pattern: Cow<'f,str>
+}
+
+impl<'f,S:Selector+fmt::Display> fmt::Display for Named<'f,S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("named({:?})", self.pattern))
+    }
+}
+
+/// Match the key or index of `step` against glob `pattern`
+fn step_matches(pattern: &str, step: Option<Step>) -> bool {
+    match step {
+        Some(Step::Key(k)) => glob_match(pattern, k),
+        Some(Step::Index(i)) => glob_match(pattern, &i.to_string()),
+        Some(Step::Synthetic) | None => false
+    }
+}
+
+impl<'f,S:Selector> Selector for Named<'f,S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if step_matches(&self.pattern, x.step()) {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Leaf<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for Leaf<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("leaf()"))
+    }
+}
+
+impl<S:Selector> Selector for Leaf<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if !has_children(x.node()) {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Descend<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for Descend<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("descend()"))
+    }
+}
+
+/// Iterator over the children of a node and their locations
+enum Children<'a> {
+    Object(btree_map::Iter<'a,String,Json>),
+    Array(iter::Enumerate<slice::Iter<'a,Json>>),
+    Empty
+}
+
+impl<'a> Children<'a> {
+    fn of(j: &'a Json) -> Children<'a> {
+        match j {
+            Json::Object(m) => Children::Object(m.iter()),
+            Json::Array(v) => Children::Array(v.iter().enumerate()),
+            _ => Children::Empty
+        }
+    }
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = (&'a Json, Step<'a>);
+
+    fn next(&mut self) -> Option<(&'a Json, Step<'a>)> {
+        match *self {
+            Children::Object(ref mut i) => i.next().map(|(k, c)| (c, Step::Key(k))),
+            Children::Array(ref mut i) => i.next().map(|(i, c)| (c, Step::Index(i))),
+            Children::Empty => None
+        }
+    }
+}
+
+fn has_children(j: &Json) -> bool {
+    match j {
+        Json::Object(m) => !m.is_empty(),
+        Json::Array(v) => !v.is_empty(),
+        _ => false
+    }
+}
+
+/// How `descend_helper` treats a descendant
+enum Visit {
+    /// Select the node and walk its children
+    Enter,
+    /// Select the node but not its children
+    Select,
+    /// Select neither the node nor its children
+    Skip
+}
+
+// Walks the subtree with an explicit stack rather than recursion so
+// that deeply nested documents can't overflow the call stack.  The
+// paths handed to `visit` and `f` are `Nested` paths borrowing
+// `ancestors`.  Nodes more than `levels` below `input` are silently
+// left out, while exceeding the maximum depth of the query limits
+// raises an error.
+fn descend_helper<'a,'b,V,F>(input: &JsonPath<'a,'b>,
+                             seen: &mut NodeSet,
+                             levels: usize,
+                             mut visit: V,
+                             mut f: F)
+                             where V: for<'c> FnMut(&JsonPath<'a,'c>) -> Visit,
+                                   F: for<'c> FnMut(&JsonPath<'a,'c>) {
+    let j = input.node();
+    if levels == 0 || !seen.insert(j) {
+        return
+    }
+    let max_depth = limits().max_depth;
+    let mut ancestors: Vec<(&'a Json, Step<'a>)> = Vec::new();
+    let mut stack = vec![Children::of(j)];
+    while let Some(children) = stack.last_mut() {
+        match children.next() {
+            Some((c, step)) => {
+                if !visit_node() {
+                    return
+                }
+                let path = Nested(c, step, &ancestors, input);
+                match visit(&path) {
+                    Visit::Enter => {
+                        f(&path);
+                        if has_children(c) && stack.len() < levels &&
+                            seen.insert(c) {
+                            if stack.len() < max_depth {
+                                ancestors.push((c, step));
+                                stack.push(Children::of(c));
+                            } else {
+                                raise(Error::DepthLimit(max_depth))
+                            }
+                        }
+                    },
+                    Visit::Select => f(&path),
+                    Visit::Skip => ()
+                }
+            },
+            None => {
+                stack.pop();
+                ancestors.pop();
+            }
+        }
+    }
+}
+
+// Breadth-first counterpart of `descend_helper`.  Every node whose
+// children are still to be walked is kept in `queue` along with the
+// index of its own parent there, from which the `ancestors` slice of
+// each child's `Nested` path is rebuilt.
+fn descend_breadth_helper<'a,'b,F>(input: &JsonPath<'a,'b>,
+                                   seen: &mut NodeSet,
+                                   mut f: F)
+                                   where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+    let j = input.node();
+    if !seen.insert(j) {
+        return
+    }
+    let max_depth = limits().max_depth;
+    let mut ancestors: Vec<(&'a Json, Step<'a>)> = Vec::new();
+    let mut queue: Vec<(&'a Json, Step<'a>, usize)> = vec![(j, Step::Index(0), 0)];
+    let mut i = 0;
+    while i < queue.len() {
+        ancestors.clear();
+        let mut k = i;
+        while k != 0 {
+            let (n, s, p) = queue[k];
+            ancestors.push((n, s));
+            k = p;
+        }
+        ancestors.reverse();
+        for (c, step) in Children::of(queue[i].0) {
+            if !visit_node() {
+                return
+            }
+            f(&Nested(c, step, &ancestors, input));
+            if has_children(c) && seen.insert(c) {
+                if ancestors.len() + 1 < max_depth {
+                    queue.push((c, step, i));
+                } else {
+                    raise(Error::DepthLimit(max_depth))
+                }
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Check whether `filter` selects any nodes from `input`
+///
+/// Filters run this way count towards the maximum filter depth of
+/// the query.
+fn selects_any<'a,'b,S:Selector>(filter: &S, input: &JsonPath<'a,'b>) -> bool {
+    let mut matches = false;
+    in_filter(|| filter.select(input, |_| matches = true));
+    matches
+}
+
+/// Run filter `f` one level deeper in the current query
+///
+/// `f` isn't run if this exceeds the maximum filter depth, which
+/// aborts the query.
+fn in_filter<F:FnOnce()>(f: F) {
+    if !counting() {
+        return f()
+    }
+    let admitted = with_current(true, |context| {
+        context.filter_depth += 1;
+        if context.filter_depth > context.limits.max_filter_depth {
+            let max = context.limits.max_filter_depth;
+            context.abort(Error::FilterDepthLimit(max))
+        }
+        !context.aborted
+    });
+    if admitted {
+        f();
+    }
+    with_current((), |context| context.filter_depth -= 1);
+}
+
+impl<S:Selector> Selector for Descend<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            descend_helper(x, &mut seen, usize::MAX, |_| Visit::Enter, |x| f(x))
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DescendBreadth<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for DescendBreadth<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("descend_breadth()"))
+    }
+}
+
+impl<S:Selector> Selector for DescendBreadth<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            descend_breadth_helper(x, &mut seen, |x| f(x))
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DescendMax<S> {
+    inner: S,
+    depth: usize
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for DescendMax<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("descend_max({})", self.depth))
+    }
+}
+
+impl<S:Selector> Selector for DescendMax<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut selected = NodeSet::new();
+        self.inner.select(input, |x| {
+            // A node already walked from an earlier input may be fewer
+            // levels below this one, so each input gets a set of its own
+            let mut seen = NodeSet::new();
+            descend_helper(x, &mut seen, self.depth, |_| Visit::Enter, |x| {
+                if selected.insert(x.node()) {
+                    f(x)
+                }
+            })
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DescendOrSelf<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for DescendOrSelf<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("descend_or_self()"))
+    }
+}
+
+impl<S:Selector> Selector for DescendOrSelf<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        let mut selected = NodeSet::new();
+        self.inner.select(input, |x| {
+            if selected.insert(x.node()) {
+                f(x)
+            }
+            descend_helper(x, &mut seen, usize::MAX, |_| Visit::Enter, |x| {
+                if selected.insert(x.node()) {
+                    f(x)
+                }
+            })
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DescendUntil<S,T> {
+    inner: S,
+    filter: T
+}
+
+impl<S:Selector+fmt::Display,T:fmt::Display> fmt::Display for DescendUntil<S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("descend_until({})", self.filter))
+    }
+}
+
+impl<S:Selector,T:Selector> Selector for DescendUntil<S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            descend_helper(x, &mut seen, usize::MAX, |x| {
+                if selects_any(&self.filter, x) {
+                    Visit::Select
+                } else {
+                    Visit::Enter
+                }
+            }, |x| f(x))
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DescendPrune<S,T> {
+    inner: S,
+    filter: T
+}
+
+impl<S:Selector+fmt::Display,T:fmt::Display> fmt::Display for DescendPrune<S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("descend_prune({})", self.filter))
+    }
+}
+
+impl<S:Selector,T:Selector> Selector for DescendPrune<S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            descend_helper(x, &mut seen, usize::MAX, |x| {
+                if selects_any(&self.filter, x) {
+                    Visit::Skip
+                } else {
+                    Visit::Enter
+                }
+            }, |x| f(x))
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ascend<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for Ascend<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("ascend()"))
+    }
+}
+
+fn ascend_helper<'a,'b,F>(input: &JsonPath<'a,'b>,
+                          seen: &mut NodeSet,
+                          mut f: F)
+                          where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+    let mut current = *input;
+    while let Some(x) = current.parent() {
+        let j = x.node();
+        if !seen.insert(j) {
+            break
+        }
+        f(&x);
+        current = x
+    }
+}
+
+impl<S:Selector> Selector for Ascend<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |n| {
+            ascend_helper(n, &mut seen, |x| f(x));
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Wherein<S,T> {
+    inner: S,
+    filter: T
+}
+
+impl<S:Selector+fmt::Display,T:fmt::Display> fmt::Display for Wherein<S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("wherein({})", self.filter))
+    }
+}
+
+impl<S:Selector,T:Selector> Selector for Wherein<S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if selects_any(&self.filter, x) {
+                f(x)
+            }
+        })
+    }
+}
+
+/// Comparison operator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cmp {
+    /// Equal
+    Eq,
+    /// Not equal
+    Ne,
+    /// Less than
+    Lt,
+    /// Less than or equal
+    Le,
+    /// Greater than
+    Gt,
+    /// Greater than or equal
+    Ge
+}
+
+impl Cmp {
+    /// Check whether the ordering `ord` satisfies the operator
+    fn test(self, ord: Option<Ordering>) -> bool {
+        match self {
+            Cmp::Eq => ord == Some(Ordering::Equal),
+            Cmp::Ne => ord != Some(Ordering::Equal),
+            Cmp::Lt => ord == Some(Ordering::Less),
+            Cmp::Le => ord == Some(Ordering::Less) || ord == Some(Ordering::Equal),
+            Cmp::Gt => ord == Some(Ordering::Greater),
+            Cmp::Ge => ord == Some(Ordering::Greater) || ord == Some(Ordering::Equal)
+        }
+    }
+}
+
+/// Compare two JSON values as described for `Selector::compare`
+fn compare_json(l: &Json, r: &Json) -> Option<Ordering> {
+    match (l, r) {
+        (&Json::U64(a), &Json::U64(b)) => Some(a.cmp(&b)),
+        (&Json::I64(a), &Json::I64(b)) => Some(a.cmp(&b)),
+        (&Json::U64(a), &Json::I64(b)) =>
+            Some(if b < 0 { Ordering::Greater } else { a.cmp(&(b as u64)) }),
+        (&Json::I64(a), &Json::U64(b)) =>
+            Some(if a < 0 { Ordering::Less } else { (a as u64).cmp(&b) }),
+        (&Json::F64(a), &Json::F64(b)) => a.partial_cmp(&b),
+        (&Json::F64(a), _) => json_int(r).and_then(|b| compare_f64_int(a, b)),
+        (_, &Json::F64(b)) => json_int(l).and_then(|a| compare_f64_int(b, a)).map(Ordering::reverse),
+        (Json::String(a), Json::String(b)) => Some(a.cmp(b)),
+        (&Json::Boolean(a), &Json::Boolean(b)) => Some(a.cmp(&b)),
+        (&Json::Null, &Json::Null) => Some(Ordering::Equal),
+        (&Json::Array(..), _) | (&Json::Object(..), _) if json_eq(l, r) => Some(Ordering::Equal),
+        _ => None
+    }
+}
+
+/// Value of a JSON integer
+fn json_int(j: &Json) -> Option<i128> {
+    match *j {
+        Json::U64(n) => Some(n as i128),
+        Json::I64(n) => Some(n as i128),
+        _ => None
+    }
+}
+
+/// Compare float `a` exactly with integer `b`
+///
+/// Converting `b` to a float would round integers beyond 2^53, so
+/// that distinct integers could compare equal to the same float.
+fn compare_f64_int(a: f64, b: i128) -> Option<Ordering> {
+    // Every JSON integer is below 2^64 in magnitude, and floats of
+    // that magnitude are integers which fit in an i128
+    if a.is_nan() {
+        None
+    } else if a.abs() >= 18446744073709551616.0 {
+        a.partial_cmp(&0.0)
+    } else {
+        let t = a.trunc();
+        Some((t as i128).cmp(&b).then(a.partial_cmp(&t)?))
+    }
+}
+
+/// Check whether two JSON values are deeply equal, comparing
+/// numbers by value
+fn json_eq(l: &Json, r: &Json) -> bool {
+    match (l, r) {
+        (Json::Array(a), Json::Array(b)) =>
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| visit_node() && json_eq(a, b)),
+        (Json::Object(a), Json::Object(b)) =>
+            a.len() == b.len() &&
+            a.iter().zip(b.iter()).all(|((ka, a), (kb, b))| ka == kb && visit_node() && json_eq(a, b)),
+        (&Json::Array(..), _) | (_, &Json::Array(..)) |
+        (&Json::Object(..), _) | (_, &Json::Object(..)) => false,
+        _ => compare_json(l, r) == Some(Ordering::Equal)
+    }
+}
+
+/// Check whether JSON value `j` contains `template`
+fn json_contains(j: &Json, template: &Json) -> bool {
+    match (j, template) {
+        (Json::Object(m), Json::Object(t)) =>
+            t.iter().all(|(k, t)| m.get(k).is_some_and(|e| json_contains(e, t))),
+        (Json::Array(v), Json::Array(t)) =>
+            t.iter().all(|t| v.iter().any(|e| json_contains(e, t))),
+        _ => json_eq(j, template)
+    }
+}
+
+/// Conversion into a borrowed or owned `Json` value
+///
+/// Selectors that compare against a `Json` value accept any type
+/// implementing this trait: a `&Json`, which the selector borrows,
+/// or a `Json`, which it takes ownership of.
+pub trait IntoJson<'j> {
+    /// Perform conversion
+    fn into_json(self) -> Cow<'j,Json>;
+}
+
+impl<'j> IntoJson<'j> for &'j Json {
+    fn into_json(self) -> Cow<'j,Json> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl IntoJson<'static> for Json {
+    fn into_json(self) -> Cow<'static,Json> {
+        Cow::Owned(self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EqualsJson<'j,S> {
+    inner: S,
+    value: Cow<'j,Json>
+}
+
+impl<'j,S:Selector+fmt::Display> fmt::Display for EqualsJson<'j,S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("equals_json({})", JsonExpr(&self.value)))
+    }
+}
+
+impl<'j,S:Selector> Selector for EqualsJson<'j,S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if json_eq(x.node(), &self.value) {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContainsJson<'j,S> {
+    inner: S,
+    template: Cow<'j,Json>
+}
+
+impl<'j,S:Selector+fmt::Display> fmt::Display for ContainsJson<'j,S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("contains_json({})", JsonExpr(&self.template)))
+    }
+}
+
+impl<'j,S:Selector> Selector for ContainsJson<'j,S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if json_contains(x.node(), &self.template) {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compare<I,S,T> {
+    inner: I,
+    left: S,
+    op: Cmp,
+    right: T
+}
+
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for Compare<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("compare({}, Cmp::{:?}, {})", self.left, self.op, self.right))
+    }
+}
+
+impl<I:Selector,S:Expr,T:Expr> Selector for Compare<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            let mut rights = Operands::new();
+            self.right.eval(x, |r| rights.push(r));
+            let mut matches = false;
+            self.left.eval(x, |l| {
+                if !matches && !aborted() {
+                    matches = rights.any(|r| self.op.test(compare_json(&l, r)))
+                }
+            });
+            if matches {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Filter<S,P> {
+    inner: S,
+    pred: P
+}
+
+impl<S:Selector+fmt::Display,P> fmt::Display for Filter<S,P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("filter(..)"))
+    }
+}
+
+impl<S:fmt::Debug,P> fmt::Debug for Filter<S,P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Filter").field("inner", &self.inner).finish_non_exhaustive()
+    }
+}
+
+impl<S:Selector,P> Selector for Filter<S,P> where P: Fn(&Json) -> bool {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if (self.pred)(x.node()) {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct FilterPath<S,P> {
+    inner: S,
+    pred: P
+}
+
+impl<S:Selector+fmt::Display,P> fmt::Display for FilterPath<S,P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("filter_path(..)"))
+    }
+}
+
+impl<S:fmt::Debug,P> fmt::Debug for FilterPath<S,P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FilterPath").field("inner", &self.inner).finish_non_exhaustive()
+    }
+}
+
+impl<S:Selector,P> Selector for FilterPath<S,P> where P: for<'a,'b> Fn(&JsonPath<'a,'b>) -> bool {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if (self.pred)(x) {
+                f(x)
+            }
+        })
+    }
+}
+
+/// Finder of the paths of nodes at or below a node
+///
+/// Direct children are found by scanning the node's children.  The
+/// first node looked up further below indexes the parent of every
+/// node below, so finding any number of them takes one traversal.
+struct Locator<'a> {
+    parents: Option<hash_map::HashMap<*const Json, (&'a Json, Step<'a>)>>
+}
+
+impl<'a> Locator<'a> {
+    fn new() -> Locator<'a> {
+        Locator { parents: None }
+    }
+
+    /// Find `target` at or below `input` and invoke `f` with its path
+    ///
+    /// `input` must be the same node on every call.
+    fn locate<'b,F>(&mut self, input: &JsonPath<'a,'b>, target: &'a Json, mut f: F)
+                    where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        if ptr::eq(input.node(), target) {
+            return f(input)
+        }
+        // Children are the common case and cheap to check first
+        if let Some((c, step)) = Children::of(input.node()).find(|&(c, _)| ptr::eq(c, target)) {
+            return f(&input.descendant(c, step))
+        }
+        let parents = self.parents.get_or_insert_with(|| index_parents(input.node()));
+        let mut ancestors = Vec::new();
+        let mut node = target;
+        while let Some(&(parent, step)) = parents.get(&(node as *const Json)) {
+            ancestors.push((node, step));
+            if ptr::eq(parent, input.node()) {
+                let (target, step) = ancestors[0];
+                ancestors.reverse();
+                ancestors.pop();
+                return f(&Nested(target, step, &ancestors, input))
+            }
+            node = parent
+        }
+    }
+}
+
+/// Map every node below `j` to its parent and its location there
+fn index_parents<'a>(j: &'a Json) -> hash_map::HashMap<*const Json, (&'a Json, Step<'a>)> {
+    let max_depth = limits().max_depth;
+    let mut parents = hash_map::HashMap::new();
+    let mut stack = vec![(j, 0)];
+    while let Some((n, depth)) = stack.pop() {
+        for (c, step) in Children::of(n) {
+            if !visit_node() {
+                return parents
+            }
+            parents.insert(c as *const Json, (n, step));
+            if has_children(c) {
+                if depth + 1 < max_depth {
+                    stack.push((c, depth + 1))
+                } else {
+                    raise(Error::DepthLimit(max_depth))
+                }
+            }
+        }
+    }
+
+    parents
+}
+
+#[derive(Clone)]
+pub struct FlatMap<S,M> {
+    inner: S,
+    f: M
+}
+
+impl<S:Selector+fmt::Display,M> fmt::Display for FlatMap<S,M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("flat_map(..)"))
+    }
+}
+
+impl<S:fmt::Debug,M> fmt::Debug for FlatMap<S,M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FlatMap").field("inner", &self.inner).finish_non_exhaustive()
+    }
+}
+
+impl<S:Selector,M> Selector for FlatMap<S,M> where M: for<'j> Fn(&'j Json) -> Vec<&'j Json> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            let mut locator = Locator::new();
+            for target in (self.f)(x.node()) {
+                locator.locate(x, target, |x| f(x))
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Union<I,S,T> {
+    inner: I,
+    left: S,
+    right: T
+}
+
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for Union<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("union({}, {})", self.left, self.right))
+    }
+}
+
+impl<I:Selector,S:Selector,T:Selector> Selector for Union<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            self.left.select(x, |x| {
+                let j = x.node();
+                if !seen.contains(j) {
+                    seen.insert(j);
+                    f(x)
+                }
+            });
+            self.right.select(x, |x| {
+                let j = x.node();
+                if !seen.contains(j) {
+                    seen.insert(j);
+                    f(x)
+                }
+            })
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Intersect<I,S,T> {
+    inner: I,
+    left: S,
+    right: T
+}
+
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for Intersect<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("intersect({}, {})", self.left, self.right))
+    }
+}
+
+impl<I:Selector,S:Selector,T:Selector> Selector for Intersect<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen_left = NodeSet::new();
+        let mut seen_right = NodeSet::new();
+        self.inner.select(input, |x| {
+            self.left.select(x, |x| {
+                let j = x.node();
+                seen_left.insert(j);
+                if seen_right.contains(j) {
+                    f(x)
+                }
+            });
+            self.right.select(x, |x| {
+                let j = x.node();
+                seen_right.insert(j);
+                if seen_left.contains(j) {
+                    f(x)
+                }
+            })
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diff<I,S,T> {
+    inner: I,
+    left: S,
+    right: T
+}
+
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for Diff<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("diff({}, {})", self.left, self.right))
+    }
+}
+
+// FIXME: this has bad asymptotic behavior
+// The results of the inner select can't be cached
+// because the path breadcrumbs have a lifetime that
+// can't escape the callback
+impl<I:Selector,S:Selector,T:Selector> Selector for Diff<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Aggregate, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            self.right.select(x, |x| {
+                seen.insert(x.node());
+            })
+        });
+        self.inner.select(input, |x| {
+            self.left.select(x, |x| {
+                if !seen.contains(x.node()) {
+                    f(x)
+                }
+            })
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AndSel<I,S,T> {
+    inner: I,
+    left: S,
+    right: T
+}
+
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for AndSel<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("and({}, {})", self.left, self.right))
+    }
+}
+
+static SINGLETON: Json = Json::Boolean(true);
+
+impl<I:Selector,S:Selector,T:Selector> Selector for AndSel<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Aggregate, f);
+        let mut found_left = false;
+        let mut found_right = false;
+        self.inner.select(input, |x| {
+            self.left.select(x, |_| found_left = true);
+            self.right.select(x, |_| found_right = true)
+        });
+        if found_left && found_right {
+            f(&input.descendant(&SINGLETON, Step::Synthetic))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrSel<I,S,T> {
+    inner: I,
+    left: S,
+    right: T
+}
+
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for OrSel<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("or({}, {})", self.left, self.right))
+    }
+}
+
+impl<I:Selector,S:Selector,T:Selector> Selector for OrSel<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Aggregate, f);
+        let mut found_left = false;
+        let mut found_right = false;
+        self.inner.select(input, |x| {
+            self.left.select(x, |_| found_left = true);
+            self.right.select(x, |_| found_right = true)
+        });
+        if found_left || found_right {
+            f(&input.descendant(&SINGLETON, Step::Synthetic))
+        }
+    }
+}
+
+/// Collection of selectors
+///
+/// This trait is implemented for tuples of up to 12 selectors of
+/// any types, and for arrays, slices and vectors of selectors of a
+/// single type.  It allows `union_all`, `any_of` and `all_of` to
+/// combine any number of selectors without nesting.  The selectors
+/// must also implement `Display`, as all selectors in this crate do.
+pub trait Selectors {
+    /// Return the number of selectors in the collection
+    fn count(&self) -> usize;
+
+    /// Run selector at `index` in the collection
+    fn select_nth<'a,'b,F>(&self, index: usize, input: &JsonPath<'a,'b>, f: F)
+                           where F: for<'c> FnMut(&JsonPath<'a,'c>);
+
+    /// Write the collection as it would be passed to `union_all`
+    fn fmt_list(&self, f: &mut fmt::Formatter) -> fmt::Result;
+}
+
+/// Adapter displaying a collection of selectors with `fmt_list`
+struct ListDisplay<'l,L:'l+?Sized>(&'l L);
+
+impl<'l,L:Selectors+?Sized> fmt::Display for ListDisplay<'l,L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt_list(f)
+    }
+}
+
+/// Write the selectors in `list` separated by commas
+fn fmt_items<S:fmt::Display>(f: &mut fmt::Formatter, list: &[S]) -> fmt::Result {
+    for (i, s) in list.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", s)?;
+    }
+    Ok(())
+}
+
+impl<S:Selector+fmt::Display> Selectors for [S] {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn select_nth<'a,'b,F>(&self, index: usize, input: &JsonPath<'a,'b>, f: F)
+                           where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        self[index].select(input, f)
+    }
+
+    fn fmt_list(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[")?;
+        fmt_items(f, self)?;
+        f.write_str("]")
+    }
+}
+
+impl<S:Selector+fmt::Display,const N: usize> Selectors for [S; N] {
+    fn count(&self) -> usize {
+        N
+    }
+
+    fn select_nth<'a,'b,F>(&self, index: usize, input: &JsonPath<'a,'b>, f: F)
+                           where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        self[index].select(input, f)
+    }
+
+    fn fmt_list(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[")?;
+        fmt_items(f, self)?;
+        f.write_str("]")
+    }
+}
+
+impl<S:Selector+fmt::Display> Selectors for Vec<S> {
+    fn count(&self) -> usize {
+        self.len()
+    }
+
+    fn select_nth<'a,'b,F>(&self, index: usize, input: &JsonPath<'a,'b>, f: F)
+                           where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        self[index].select(input, f)
+    }
+
+    fn fmt_list(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[")?;
+        fmt_items(f, self)?;
+        f.write_str("]")
+    }
+}
+
+impl<L:Selectors+?Sized> Selectors for &L {
+    fn count(&self) -> usize {
+        (**self).count()
+    }
+
+    fn select_nth<'a,'b,F>(&self, index: usize, input: &JsonPath<'a,'b>, f: F)
+                           where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        (**self).select_nth(index, input, f)
+    }
+
+    fn fmt_list(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        (**self).fmt_list(f)
+    }
+}
+
+macro_rules! tuple_selectors {
+    ($count:expr; $($index:tt: $name:ident),+) => {
+        impl<$($name:Selector+fmt::Display),+> Selectors for ($($name,)+) {
+            fn count(&self) -> usize {
+                $count
+            }
+
+            fn select_nth<'a,'b,F>(&self, index: usize, input: &JsonPath<'a,'b>, f: F)
+                                   where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+                match index {
+                    $($index => self.$index.select(input, f),)+
+                    _ => panic!("selector index {} out of range", index)
+                }
+            }
+
+            fn fmt_list(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("(")?;
+                $(
+                    if $index > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", self.$index)?;
+                )+
+                if $count == 1 {
+                    f.write_str(",")?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+tuple_selectors!(1; 0: A);
+tuple_selectors!(2; 0: A, 1: B);
+tuple_selectors!(3; 0: A, 1: B, 2: C);
+tuple_selectors!(4; 0: A, 1: B, 2: C, 3: D);
+tuple_selectors!(5; 0: A, 1: B, 2: C, 3: D, 4: E);
+tuple_selectors!(6; 0: A, 1: B, 2: C, 3: D, 4: E, 5: G);
+tuple_selectors!(7; 0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H);
+tuple_selectors!(8; 0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I);
+tuple_selectors!(9; 0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I, 8: J);
+tuple_selectors!(10; 0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I, 8: J, 9: K);
+tuple_selectors!(11; 0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I, 8: J, 9: K, 10: L);
+tuple_selectors!(12; 0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I, 8: J, 9: K, 10: L, 11: M);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnionAll<I,L> {
+    inner: I,
+    list: L
+}
+
+impl<I:Selector+fmt::Display,L:Selectors> fmt::Display for UnionAll<I,L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("union_all({})", ListDisplay(&self.list)))
+    }
+}
+
+impl<I:Selector,L:Selectors> Selector for UnionAll<I,L> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = NodeSet::new();
+        self.inner.select(input, |x| {
+            for i in 0..self.list.count() {
+                self.list.select_nth(i, x, |x| {
+                    if seen.insert(x.node()) {
+                        f(x)
+                    }
+                })
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnyOf<I,L> {
+    inner: I,
+    list: L
+}
+
+impl<I:Selector+fmt::Display,L:Selectors> fmt::Display for AnyOf<I,L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("any_of({})", ListDisplay(&self.list)))
+    }
+}
+
+impl<I:Selector,L:Selectors> Selector for AnyOf<I,L> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Aggregate, f);
+        let mut found = false;
+        self.inner.select(input, |x| {
+            for i in 0..self.list.count() {
+                if found {
+                    break
+                }
+                self.list.select_nth(i, x, |_| found = true)
+            }
+        });
+        if found {
+            f(&input.descendant(&SINGLETON, Step::Synthetic))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllOf<I,L> {
+    inner: I,
+    list: L
+}
+
+impl<I:Selector+fmt::Display,L:Selectors> fmt::Display for AllOf<I,L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("all_of({})", ListDisplay(&self.list)))
+    }
+}
+
+impl<I:Selector,L:Selectors> Selector for AllOf<I,L> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Aggregate, f);
+        let mut found = vec![false; self.list.count()];
+        self.inner.select(input, |x| {
+            for (i, found) in found.iter_mut().enumerate() {
+                if !*found {
+                    self.list.select_nth(i, x, |_| *found = true)
+                }
+            }
+        });
+        if found.iter().all(|&found| found) {
+            f(&input.descendant(&SINGLETON, Step::Synthetic))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IfThenElse<I,C,T,E> {
+    inner: I,
+    cond: C,
+    then: T,
+    otherwise: E
+}
+
+impl<I:Selector+fmt::Display,C:fmt::Display,T:fmt::Display,E:fmt::Display> fmt::Display for IfThenElse<I,C,T,E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("if_then_else({}, {}, {})", self.cond, self.then, self.otherwise))
+    }
+}
+
+impl<I:Selector,C:Selector,T:Selector,E:Selector> Selector for IfThenElse<I,C,T,E> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            if selects_any(&self.cond, x) {
+                self.then.select(x, |x| f(x))
+            } else {
+                self.otherwise.select(x, |x| f(x))
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coalesce<I,L> {
+    inner: I,
+    list: L
+}
+
+impl<I:Selector+fmt::Display,L:Selectors> fmt::Display for Coalesce<I,L> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("coalesce({})", ListDisplay(&self.list)))
+    }
+}
+
+impl<I:Selector,L:Selectors> Selector for Coalesce<I,L> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.inner.select(input, |x| {
+            let mut found = false;
+            for i in 0..self.list.count() {
+                self.list.select_nth(i, x, |x| {
+                    found = true;
+                    f(x)
+                });
+                if found {
+                    break
+                }
+            }
+        })
+    }
+}
+
+/// JSON expression trait
+///
+/// Implementors of this trait compute values from the node at a
+/// given path.  Unlike a selector, whose results are always nodes of
+/// the document, an expression may compute new values such as the
+/// sum of two numbers.  Every selector is an expression whose values
+/// are the nodes it selects, so selectors can be used as operands.
+///
+/// Arithmetic is offered as the methods `add`, `sub`, `mul` and
+/// `div` only, not as the operators of `std::ops`: those can't be
+/// implemented for every selector from this crate.
+pub trait Expr: Sized {
+    /// Evaluate expression
+    ///
+    /// Given the path to a single node, `input`, this method should
+    /// compute the values of the expression and invoke the closure
+    /// `f` with each.
+    fn eval<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                     where F: FnMut(Cow<'a,Json>);
+
+    /// Add values
+    ///
+    /// Computes the sum of each value of the current expression with
+    /// each value of `right`.  Operands which are not numbers produce
+    /// no values.  Integer results are computed exactly if they fit
+    /// in a `Json::U64` or `Json::I64`, otherwise in floating point.
+    #[inline]
+    fn add<R:Expr>(self, right: R) -> Arith<Self,R> {
+        Arith { left: self, op: ArithOp::Add, right }
+    }
+
+    /// Subtract values
+    ///
+    /// Like `add`, but computes differences.
+    #[inline]
+    fn sub<R:Expr>(self, right: R) -> Arith<Self,R> {
+        Arith { left: self, op: ArithOp::Sub, right }
+    }
+
+    /// Multiply values
+    ///
+    /// Like `add`, but computes products.
+    #[inline]
+    fn mul<R:Expr>(self, right: R) -> Arith<Self,R> {
+        Arith { left: self, op: ArithOp::Mul, right }
+    }
+
+    /// Divide values
+    ///
+    /// Like `add`, but computes quotients.  The quotient of two
+    /// integers is an integer only if the division is exact.
+    /// Division by zero produces no value.
+    #[inline]
+    fn div<R:Expr>(self, right: R) -> Arith<Self,R> {
+        Arith { left: self, op: ArithOp::Div, right }
+    }
+
+    /// Concatenate values
+    ///
+    /// Concatenates each value of the current expression with each
+    /// value of `right` if both are strings or both are lists.
+    /// Other operands produce no values.
+    #[inline]
+    fn concat<R:Expr>(self, right: R) -> Concat<Self,R> {
+        Concat { left: self, right }
+    }
+
+    /// Compute length of values
+    ///
+    /// Computes the number of characters in a string, elements in a
+    /// list or keys in an object.  Other values produce no value.
+    #[inline]
+    fn length(self) -> Apply<Self> {
+        Apply { inner: self, func: Func::Length }
+    }
+
+    /// Convert strings to lower case
+    #[inline]
+    fn lower(self) -> Apply<Self> {
+        Apply { inner: self, func: Func::Lower }
+    }
+
+    /// Convert strings to upper case
+    #[inline]
+    fn upper(self) -> Apply<Self> {
+        Apply { inner: self, func: Func::Upper }
+    }
+
+    /// Convert values to numbers
+    ///
+    /// Numbers are unchanged, and strings containing a number are
+    /// parsed.  Other values produce no value.
+    #[inline]
+    fn to_number(self) -> Apply<Self> {
+        Apply { inner: self, func: Func::ToNumber }
+    }
+
+    /// Convert values to strings
+    ///
+    /// Strings are unchanged, and other values are converted to
+    /// their JSON text.  This isn't called `to_string`, which
+    /// selectors already have from `ToString` through `Display`.
+    #[inline]
+    fn to_text(self) -> Apply<Self> {
+        Apply { inner: self, func: Func::ToText }
+    }
+}
+
+impl<S:Selector> Expr for S {
+    fn eval<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+                     where F: FnMut(Cow<'a,Json>) {
+        self.select(input, |x| f(Cow::Borrowed(x.node())))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lit {
+    value: Json
+}
+
+impl fmt::Display for Lit {
+    /// Write the call to `lit` building the literal, passing a
+    /// reference to a Rust value of the type converting to it
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Json::Boolean(b) => write!(f, "lit(&{})", b),
+            Json::U64(v) => write!(f, "lit(&{}u64)", v),
+            Json::I64(v) => write!(f, "lit(&{}i64)", v),
+            Json::F64(v) if v.is_finite() => write!(f, "lit(&{:?}f64)", v),
+            Json::String(ref s) => write!(f, "lit({:?})", s),
+            ref j => write!(f, "lit(&{})", JsonExpr(j))
+        }
+    }
+}
+
+impl Expr for Lit {
+    fn eval<'a,'b,F>(&self, _: &JsonPath<'a,'b>, mut f: F)
+                     where F: FnMut(Cow<'a,Json>) {
+        f(Cow::Owned(self.value.clone()))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Map<S,E> {
+    inner: S,
+    expr: E
+}
+
+impl<S:Selector+fmt::Display,E:fmt::Display> fmt::Display for Map<S,E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_call(f, &self.inner, format_args!("map({})", self.expr))
+    }
+}
+
+impl<S:Selector,E:Expr> Expr for Map<S,E> {
+    fn eval<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+                     where F: FnMut(Cow<'a,Json>) {
+        self.inner.select(input, |x| self.expr.eval(x, &mut f))
+    }
+}
+
+/// Arithmetic operator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArithOp {
+    /// `add(right)`
+    Add,
+    /// `sub(right)`
+    Sub,
+    /// `mul(right)`
+    Mul,
+    /// `div(right)`
+    Div
+}
+
+impl fmt::Display for ArithOp {
+    /// Write the name of the method applying the operator
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ArithOp::Add => "add",
+            ArithOp::Sub => "sub",
+            ArithOp::Mul => "mul",
+            ArithOp::Div => "div"
+        })
+    }
+}
+
+/// Convert an integer result to the narrowest fitting JSON number
+fn int_json(v: i128) -> Json {
+    if v >= 0 && v <= u64::MAX as i128 {
+        Json::U64(v as u64)
+    } else if v >= i64::MIN as i128 && v <= i64::MAX as i128 {
+        Json::I64(v as i64)
+    } else {
+        Json::F64(v as f64)
+    }
+}
+
+fn as_int(j: &Json) -> Option<i128> {
+    match *j {
+        Json::U64(v) => Some(v as i128),
+        Json::I64(v) => Some(v as i128),
+        _ => None
+    }
+}
+
+fn arith_json(op: ArithOp, l: &Json, r: &Json) -> Option<Json> {
+    match (as_int(l), as_int(r)) {
+        (Some(a), Some(b)) => {
+            let v = match op {
+                ArithOp::Add => a.checked_add(b),
+                ArithOp::Sub => a.checked_sub(b),
+                ArithOp::Mul => a.checked_mul(b),
+                ArithOp::Div if b == 0 => return None,
+                ArithOp::Div if a % b == 0 => a.checked_div(b),
+                ArithOp::Div => None
+            };
+            // Results which aren't integers or overflow are floats
+            match v {
+                Some(v) => Some(int_json(v)),
+                None => float_arith(op, a as f64, b as f64)
+            }
+        },
+        _ => match (l.as_f64(), r.as_f64()) {
+            (Some(a), Some(b)) => float_arith(op, a, b),
+            _ => None
+        }
+    }
+}
+
+fn float_arith(op: ArithOp, a: f64, b: f64) -> Option<Json> {
+    match op {
+        ArithOp::Add => Some(Json::F64(a + b)),
+        ArithOp::Sub => Some(Json::F64(a - b)),
+        ArithOp::Mul => Some(Json::F64(a * b)),
+        ArithOp::Div if b == 0.0 => None,
+        ArithOp::Div => Some(Json::F64(a / b))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Arith<L,R> {
+    left: L,
+    op: ArithOp,
+    right: R
+}
+
+impl<L:fmt::Display,R:fmt::Display> fmt::Display for Arith<L,R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}({})", self.left, self.op, self.right)
+    }
+}
+
+impl<L:Expr,R:Expr> Expr for Arith<L,R> {
+    fn eval<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+                     where F: FnMut(Cow<'a,Json>) {
+        let mut rights = Operands::new();
+        self.right.eval(input, |r| rights.push(r));
+        self.left.eval(input, |l| {
+            rights.for_each(|r| {
+                if let Some(v) = arith_json(self.op, &l, r) {
+                    f(Cow::Owned(v))
+                }
+            })
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Concat<L,R> {
+    left: L,
+    right: R
+}
+
+impl<L:fmt::Display,R:fmt::Display> fmt::Display for Concat<L,R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.concat({})", self.left, self.right)
+    }
+}
+
+fn concat_json(l: &Json, r: &Json) -> Option<Json> {
+    match (l, r) {
+        (Json::String(a), Json::String(b)) => Some(Json::String(format!("{}{}", a, b))),
+        (Json::Array(a), Json::Array(b)) =>
+            Some(Json::Array(a.iter().chain(b.iter()).cloned().collect())),
+        _ => None
+    }
+}
+
+impl<L:Expr,R:Expr> Expr for Concat<L,R> {
+    fn eval<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+                     where F: FnMut(Cow<'a,Json>) {
+        let mut rights = Operands::new();
+        self.right.eval(input, |r| rights.push(r));
+        self.left.eval(input, |l| {
+            rights.for_each(|r| {
+                if let Some(v) = concat_json(&l, r) {
+                    f(Cow::Owned(v))
+                }
+            })
+        })
+    }
+}
+
+/// Function applied by `Apply`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Func {
+    /// `length()`
+    Length,
+    /// `lower()`
+    Lower,
+    /// `upper()`
+    Upper,
+    /// `to_number()`
+    ToNumber,
+    /// `to_text()`
+    ToText
+}
+
+impl fmt::Display for Func {
+    /// Write the name of the method applying the function
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Func::Length => "length",
+            Func::Lower => "lower",
+            Func::Upper => "upper",
+            Func::ToNumber => "to_number",
+            Func::ToText => "to_text"
+        })
+    }
+}
+
+fn parse_number(s: &str) -> Option<Json> {
+    let s = s.trim();
+    if let Ok(v) = s.parse::<u64>() {
+        return Some(Json::U64(v))
+    }
+    if let Ok(v) = s.parse::<i64>() {
+        return Some(Json::I64(v))
+    }
+    match s.parse::<f64>() {
+        Ok(v) if v.is_finite() => Some(Json::F64(v)),
+        _ => None
+    }
+}
+
+impl Func {
+    /// Apply the function to `v`, returning `None` if it has no
+    /// value for it
+    pub fn apply<'a>(self, v: Cow<'a,Json>) -> Option<Cow<'a,Json>> {
+        match (self, &*v) {
+            (Func::Length, Json::String(s)) => Some(Cow::Owned(Json::U64(s.chars().count() as u64))),
+            (Func::Length, Json::Array(a)) => Some(Cow::Owned(Json::U64(a.len() as u64))),
+            (Func::Length, Json::Object(m)) => Some(Cow::Owned(Json::U64(m.len() as u64))),
+            (Func::Lower, Json::String(s)) => Some(Cow::Owned(Json::String(s.to_lowercase()))),
+            (Func::Upper, Json::String(s)) => Some(Cow::Owned(Json::String(s.to_uppercase()))),
+            (Func::ToNumber, &Json::U64(..)) |
+            (Func::ToNumber, &Json::I64(..)) |
+            (Func::ToNumber, &Json::F64(..)) |
+            (Func::ToText, &Json::String(..)) => Some(v),
+            (Func::ToNumber, Json::String(s)) => parse_number(s).map(Cow::Owned),
+            (Func::ToText, j) => Some(Cow::Owned(Json::String(j.to_string()))),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Apply<E> {
+    inner: E,
+    func: Func
+}
+
+impl<E:fmt::Display> fmt::Display for Apply<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}()", self.inner, self.func)
+    }
+}
+
+impl<E:Expr> Expr for Apply<E> {
+    fn eval<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+                     where F: FnMut(Cow<'a,Json>) {
+        self.inner.eval(input, |v| {
+            if let Some(v) = self.func.apply(v) {
+                f(v)
+            }
+        })
+    }
+}
+
+fn hash_json<H:Hasher>(j: &Json, h: &mut H) {
+    match j {
+        // Numbers equal by value such as 1 and 1.0, or 0.0 and -0.0,
+        // must hash equal, so integral floats which may equal a JSON
+        // integer hash as that integer, and other floats by their bits
+        &Json::I64(..) | &Json::U64(..) => {
+            0u8.hash(h);
+            json_int(j).hash(h)
+        },
+        &Json::F64(v) if v.fract() == 0.0 && v.abs() < 18446744073709551616.0 => {
+            0u8.hash(h);
+            Some(v as i128).hash(h)
+        },
+        &Json::F64(v) => { 1u8.hash(h); v.to_bits().hash(h) },
+        Json::String(s) => { 3u8.hash(h); s.hash(h) },
+        &Json::Boolean(b) => { 4u8.hash(h); b.hash(h) },
+        Json::Array(v) => {
+            5u8.hash(h);
+            v.len().hash(h);
+            for e in v.iter() {
+                hash_json(e, h)
+            }
+        },
+        Json::Object(m) => {
+            6u8.hash(h);
+            m.len().hash(h);
+            for (k,e) in m.iter() {
+                k.hash(h);
+                hash_json(e, h)
+            }
+        },
+        &Json::Null => 7u8.hash(h)
+    }
+}
+
+/// Set of JSON nodes compared by value
+///
+/// Values are compared by `json_eq`, so numbers are equal if their
+/// values are.
+/// Like `NodeSet`, creating a set and inserting into it are counted
+/// when profiling, and its entries count towards the maximum set
+/// memory.
+struct ValueSet<'a> {
+    buckets: hash_map::HashMap<u64, Vec<&'a Json>>,
+    len: usize
+}
+
+impl<'a> Drop for ValueSet<'a> {
+    fn drop(&mut self) {
+        track_set_memory(-((self.len * SET_ENTRY_SIZE) as isize))
+    }
+}
+
+impl<'a> ValueSet<'a> {
+    fn new() -> ValueSet<'a> {
+        tally(|c| c.sets_created += 1);
+        ValueSet { buckets: hash_map::HashMap::new(), len: 0 }
+    }
+
+    fn hash(j: &Json) -> u64 {
+        let mut h = hash_map::DefaultHasher::new();
+        hash_json(j, &mut h);
+        h.finish()
+    }
+
+    fn contains(&self, j: &Json) -> bool {
+        match self.buckets.get(&ValueSet::hash(j)) {
+            Some(b) => b.iter().any(|e| json_eq(e, j)),
+            None => false
+        }
+    }
+
+    /// Insert `j`, returning `true` if no equal value was present
+    fn insert(&mut self, j: &'a Json) -> bool {
+        tally(|c| c.set_insertions += 1);
+        let b = self.buckets.entry(ValueSet::hash(j)).or_default();
+        if b.iter().any(|e| json_eq(e, j)) {
+            false
+        } else {
+            b.push(j);
+            self.len += 1;
+            track_set_memory(SET_ENTRY_SIZE as isize);
+            true
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Distinct<S> {
+    inner: S
+}
+
+impl<S:Selector+fmt::Display> fmt::Display for Distinct<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_call(f, &self.inner, format_args!("distinct()"))
+    }
+}
+
+impl<S:Selector> Selector for Distinct<S> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = ValueSet::new();
+        self.inner.select(input, |x| {
+            if seen.insert(x.node()) {
+                f(x)
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnionValues<I,S,T> {
+    inner: I,
+    left: S,
+    right: T
+}
+
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for UnionValues<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("union_values({}, {})", self.left, self.right))
+    }
+}
+
+impl<I:Selector,S:Selector,T:Selector> Selector for UnionValues<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
                        where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        let mut seen = hash_set::HashSet::new();
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen = ValueSet::new();
         self.inner.select(input, |x| {
             self.left.select(x, |x| {
-                let j = x.node();
-                if !seen.contains(&(j as *const Json)) {
-                    seen.insert(j as *const Json);
+                if seen.insert(x.node()) {
                     f(x)
                 }
             });
             self.right.select(x, |x| {
-                let j = x.node();
-                if !seen.contains(&(j as *const Json)) {
-                    seen.insert(j as *const Json);
+                if seen.insert(x.node()) {
                     f(x)
                 }
             })
@@ -782,29 +4569,38 @@ impl<I:Selector,S:Selector,T:Selector> Selector for Union<I,S,T> {
     }
 }
 
-pub struct Intersect<I,S,T> {
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntersectValues<I,S,T> {
     inner: I,
     left: S,
     right: T
 }
 
-impl<I:Selector,S:Selector,T:Selector> Selector for Intersect<I,S,T> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for IntersectValues<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("intersect_values({}, {})", self.left, self.right))
+    }
+}
+
+impl<I:Selector,S:Selector,T:Selector> Selector for IntersectValues<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
                        where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        let mut seen_left = hash_set::HashSet::new();
-        let mut seen_right = hash_set::HashSet::new();
+        let mut f = probe(self, input, Flow::Stream, f);
+        let mut seen_left = ValueSet::new();
+        let mut seen_right = ValueSet::new();
+        let mut selected = ValueSet::new();
         self.inner.select(input, |x| {
             self.left.select(x, |x| {
                 let j = x.node();
-                seen_left.insert(j as *const Json);
-                if seen_right.contains(&(j as *const Json)) {
+                seen_left.insert(j);
+                if seen_right.contains(j) && selected.insert(j) {
                     f(x)
                 }
             });
             self.right.select(x, |x| {
                 let j = x.node();
-                seen_right.insert(j as *const Json);
-                if seen_left.contains(&(j as *const Json)) {
+                seen_right.insert(j);
+                if seen_left.contains(j) && selected.insert(j) {
                     f(x)
                 }
             })
@@ -812,28 +4608,34 @@ impl<I:Selector,S:Selector,T:Selector> Selector for Intersect<I,S,T> {
     }
 }
 
-pub struct Diff<I,S,T> {
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiffValues<I,S,T> {
     inner: I,
     left: S,
     right: T
 }
 
-// FIXME: this has bad asymptotic behavior
-// The results of the inner select can't be cached
-// because the path breadcrumbs have a lifetime that
-// can't escape the callback
-impl<I:Selector,S:Selector,T:Selector> Selector for Diff<I,S,T> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+impl<I:Selector+fmt::Display,S:fmt::Display,T:fmt::Display> fmt::Display for DiffValues<I,S,T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_chain(f, &self.inner, format_args!("diff_values({}, {})", self.left, self.right))
+    }
+}
+
+impl<I:Selector,S:Selector,T:Selector> Selector for DiffValues<I,S,T> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
                        where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        let mut seen = hash_set::HashSet::new();
+        let mut f = probe(self, input, Flow::Aggregate, f);
+        let mut seen = ValueSet::new();
+        let mut selected = ValueSet::new();
         self.inner.select(input, |x| {
             self.right.select(x, |x| {
-                seen.insert(x.node() as *const Json);
+                seen.insert(x.node());
             })
         });
         self.inner.select(input, |x| {
             self.left.select(x, |x| {
-                if !seen.contains(&(x.node() as *const Json)) {
+                let j = x.node();
+                if !seen.contains(j) && selected.insert(j) {
                     f(x)
                 }
             })
@@ -841,71 +4643,134 @@ impl<I:Selector,S:Selector,T:Selector> Selector for Diff<I,S,T> {
     }
 }
 
-pub struct AndSel<I,S,T> {
-    inner: I,
-    left: S,
-    right: T
+/// Extension trait for `Json`
+pub trait JsonExt {
+    /// Run query
+    ///
+    /// Runs the query represented by the selector `s`
+    /// against the JSON document, accumulating and
+    /// returning the results in a new vector.  No `Limits` apply,
+    /// so the whole document is searched however deep it is.
+    fn query<S:Selector>(&self, s: S) -> Vec<&Json>;
+
+    /// Run query, reporting errors
+    ///
+    /// Like `query`, but runs the query under the default `Limits`
+    /// and returns an error if it exceeded one of them.
+    fn try_query<S:Selector>(&self, s: S) -> Result<Vec<&Json>, Error>;
+
+    /// Run query with limits
+    ///
+    /// Like `try_query`, but uses the given `limits` rather than
+    /// the defaults.
+    fn try_query_with<S:Selector>(&self, s: S, limits: &Limits) -> Result<Vec<&Json>, Error>;
+
+    /// Run query, tracing its execution
+    ///
+    /// Like `query`, but also returns a `Trace` recording how many
+    /// nodes each selector in the query received and selected.  This
+    /// helps find out which part of a query dropped the nodes
+    /// expected in its results.
+    fn trace<S:Selector>(&self, s: S) -> (Vec<&Json>, Trace);
+
+    /// Run query, profiling its execution
+    ///
+    /// Like `query`, but also returns a `Profile` counting the
+    /// nodes visited, the sets of nodes created and inserted into,
+    /// and the time spent by each type of selector in the query.
+    /// Profiling slows the query down, so times are only useful
+    /// relative to each other.
+    fn profile<S:Selector>(&self, s: S) -> (Vec<&Json>, Profile);
+
+    /// Evaluate expression
+    ///
+    /// Evaluates the expression `e` against the JSON document,
+    /// accumulating and returning its values in a new vector.
+    fn eval<E:Expr>(&self, e: E) -> Vec<Json>;
 }
 
-static SINGLETON: Json = Json::Boolean(true);
+impl JsonExt for Json {
+    fn query<S:Selector>(&self, s: S) -> Vec<&Json> {
+        collect_unlimited(|push| s.select(&JsonPath::root(self), |x| push(x.node())))
+    }
 
-impl<I:Selector,S:Selector,T:Selector> Selector for AndSel<I,S,T> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        let mut found_left = false;
-        let mut found_right = false;
-        self.inner.select(input, |x| {
-            self.left.select(x, |_| found_left = true);
-            self.right.select(x, |_| found_right = true)
+    fn try_query<S:Selector>(&self, s: S) -> Result<Vec<&Json>, Error> {
+        self.try_query_with(s, &Limits::new())
+    }
+
+    fn try_query_with<S:Selector>(&self, s: S, limits: &Limits) -> Result<Vec<&Json>, Error> {
+        let (outvec, context) = collect(Context::new(limits), |push| {
+            s.select(&JsonPath::root(self), |x| push(x.node()))
         });
-        if found_left && found_right {
-            f(&input.descendant(&SINGLETON))
+        match context.error {
+            Some(e) => Err(e),
+            None => Ok(outvec)
         }
     }
-}
 
-pub struct OrSel<I,S,T> {
-    inner: I,
-    left: S,
-    right: T
-}
+    fn trace<S:Selector>(&self, s: S) -> (Vec<&Json>, Trace) {
+        let mut context = Context::new(&Limits::unlimited());
+        context.tracer = Some(Tracer::new());
+        let (outvec, context) = collect(context, |push| {
+            s.select(&JsonPath::root(self), |x| push(x.node()))
+        });
+        let tracer = context.tracer.expect("tracer missing");
+        let trace = Trace {
+            name: "query",
+            input: 1,
+            output: outvec.len(),
+            rejected: Vec::new(),
+            children: tracer.roots.iter().map(|&r| tracer.finish(r)).collect()
+        };
 
-impl<I:Selector,S:Selector,T:Selector> Selector for OrSel<I,S,T> {
-    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
-                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
-        let mut found_left = false;
-        let mut found_right = false;
-        self.inner.select(input, |x| {
-            self.left.select(x, |_| found_left = true);
-            self.right.select(x, |_| found_right = true)
+        (outvec, trace)
+    }
+
+    fn profile<S:Selector>(&self, s: S) -> (Vec<&Json>, Profile) {
+        let mut context = Context::new(&Limits::unlimited());
+        context.profiler = Some(Profiler::new());
+        let (outvec, context) = collect(context, |push| {
+            s.select(&JsonPath::root(self), |x| push(x.node()))
         });
-        if found_left || found_right {
-            f(&input.descendant(&SINGLETON))
-        }
+        let profile = context.profiler.expect("profiler missing").finish();
+
+        (outvec, profile)
+    }
+
+    fn eval<E:Expr>(&self, e: E) -> Vec<Json> {
+        collect_unlimited(|push| e.eval(&JsonPath::root(self), |v| push(v.into_owned())))
     }
 }
 
-/// Extension trait for `Json`
-pub trait JsonExt {
-    /// Run query
-    ///
-    /// Runs the query represented by the selector `s`
-    /// against the JSON document, accumulating and
-    /// returning the results in a new vector.
-    fn query<S:Selector>(&self, s: S) -> Vec<&Json>;
+/// Run `f` in the query context `context`, collecting the results it
+/// passes to its sink
+///
+/// Results exceeding the result limit are left out.  Returns the
+/// results along with the context, whose `error` tells whether the
+/// query was aborted.
+fn collect<T,F>(context: Context, f: F) -> (Vec<T>, Context)
+    where F: FnOnce(&mut dyn FnMut(T)) {
+    let mut outvec = Vec::new();
+    let context = run_context(context, || {
+        f(&mut |x| {
+            if admit_result() {
+                outvec.push(x)
+            }
+        })
+    });
+
+    (outvec, context)
 }
 
-impl JsonExt for Json {
-    fn query<S:Selector>(&self, s: S) -> Vec<&Json> {
-        let mut outvec = Vec::new();
-        {
-            s.select(&JsonPath::root(self), |x| {
-                outvec.push(x.node())
-            });
-        }
-        
-        outvec
-    }
+/// Run `f` without limits, collecting the results it passes to its
+/// sink
+///
+/// Only limits abort a query, so there is no error to report.
+fn collect_unlimited<T,F>(f: F) -> Vec<T>
+    where F: FnOnce(&mut dyn FnMut(T)) {
+    let (outvec, context) = collect(Context::new(&Limits::unlimited()), f);
+    debug_assert!(context.error.is_none(), "query without limits aborted");
+    outvec
 }
 
 /// Create trivial selector
@@ -919,6 +4784,15 @@ pub fn node() -> Node {
     Node { _dummy: () }
 }
 
+/// Create literal expression
+///
+/// Creates an expression with the single value `value`, for use
+/// as an operand of other expressions and comparisons.
+#[inline]
+pub fn lit<T:ToJson+?Sized>(value: &T) -> Lit {
+    Lit { value: value.to_json() }
+}
+
 /// Shorthand for `node().boolean()`
 #[inline]
 pub fn boolean() -> BooleanSel<Node> {
@@ -985,6 +4859,36 @@ pub fn descend() -> Descend<Node> {
     node().descend()
 }
 
+/// Shorthand for `node().descend_breadth()`
+#[inline]
+pub fn descend_breadth() -> DescendBreadth<Node> {
+    node().descend_breadth()
+}
+
+/// Shorthand for `node().descend_max(depth)`
+#[inline]
+pub fn descend_max(depth: usize) -> DescendMax<Node> {
+    node().descend_max(depth)
+}
+
+/// Shorthand for `node().descend_or_self()`
+#[inline]
+pub fn descend_or_self() -> DescendOrSelf<Node> {
+    node().descend_or_self()
+}
+
+/// Shorthand for `node().descend_until(filter)`
+#[inline]
+pub fn descend_until<T:Selector>(filter: T) -> DescendUntil<Node,T> {
+    node().descend_until(filter)
+}
+
+/// Shorthand for `node().descend_prune(filter)`
+#[inline]
+pub fn descend_prune<T:Selector>(filter: T) -> DescendPrune<Node,T> {
+    node().descend_prune(filter)
+}
+
 /// Shorthand for `node().ascend()`
 #[inline]
 pub fn ascend() -> Ascend<Node> {
@@ -993,55 +4897,233 @@ pub fn ascend() -> Ascend<Node> {
 
 /// Shorthand for `node().at(index)`
 #[inline]
-pub fn at(index: usize) -> At<Node> {
-    node().at(index)
+pub fn at(index: usize) -> At<Node> {
+    node().at(index)
+}
+
+/// Shorthand for `node().key(name)`
+#[inline]
+pub fn key<'a,N:Into<Cow<'a,str>>>(name: N) -> Key<'a, Node> {
+    node().key(name)
+}
+
+/// Shorthand for `node().root()`
+#[inline]
+pub fn root() -> RootSel<Node> {
+    node().root()
+}
+
+/// Shorthand for `node().next_sibling()`
+#[inline]
+pub fn next_sibling() -> Sibling<Node> {
+    node().next_sibling()
+}
+
+/// Shorthand for `node().prev_sibling()`
+#[inline]
+pub fn prev_sibling() -> Sibling<Node> {
+    node().prev_sibling()
+}
+
+/// Shorthand for `node().following_siblings()`
+#[inline]
+pub fn following_siblings() -> Sibling<Node> {
+    node().following_siblings()
+}
+
+/// Shorthand for `node().preceding_siblings()`
+#[inline]
+pub fn preceding_siblings() -> Sibling<Node> {
+    node().preceding_siblings()
+}
+
+/// Shorthand for `node().siblings()`
+#[inline]
+pub fn siblings() -> Sibling<Node> {
+    node().siblings()
+}
+
+/// Shorthand for `node().first()`
+#[inline]
+pub fn first() -> PositionSel<Node> {
+    node().first()
+}
+
+/// Shorthand for `node().last()`
+#[inline]
+pub fn last() -> PositionSel<Node> {
+    node().last()
+}
+
+/// Shorthand for `node().nth(index)`
+#[inline]
+pub fn nth(index: usize) -> PositionSel<Node> {
+    node().nth(index)
+}
+
+/// Shorthand for `node().even()`
+#[inline]
+pub fn even() -> PositionSel<Node> {
+    node().even()
+}
+
+/// Shorthand for `node().odd()`
+#[inline]
+pub fn odd() -> PositionSel<Node> {
+    node().odd()
+}
+
+/// Shorthand for `node().at_depth(depth)`
+#[inline]
+pub fn at_depth(depth: usize) -> AtDepth<Node> {
+    node().at_depth(depth)
+}
+
+/// Shorthand for `node().named(pattern)`
+#[inline]
+pub fn named<'a,P:Into<Cow<'a,str>>>(pattern: P) -> Named<'a, Node> {
+    node().named(pattern)
+}
+
+/// Shorthand for `node().leaf()`
+#[inline]
+pub fn leaf() -> Leaf<Node> {
+    node().leaf()
+}
+
+/// Shorthand for `node().wherein(filter)`
+#[inline]
+pub fn wherein<T:Selector>(filter: T) -> Wherein<Node,T> {
+    node().wherein(filter)
+}
+
+/// Shorthand for `node().compare(left, op, right)`
+#[inline]
+pub fn compare<T1:Expr,T2:Expr>(left: T1, op: Cmp, right: T2) -> Compare<Node,T1,T2> {
+    node().compare(left, op, right)
+}
+
+/// Shorthand for `node().equals_sel(left, right)`
+#[inline]
+pub fn equals_sel<T1:Expr,T2:Expr>(left: T1, right: T2) -> Compare<Node,T1,T2> {
+    node().equals_sel(left, right)
+}
+
+/// Shorthand for `node().equals_json(value)`
+#[inline]
+pub fn equals_json<'a,J:IntoJson<'a>>(value: J) -> EqualsJson<'a, Node> {
+    node().equals_json(value)
+}
+
+/// Shorthand for `node().contains_json(template)`
+#[inline]
+pub fn contains_json<'a,J:IntoJson<'a>>(template: J) -> ContainsJson<'a, Node> {
+    node().contains_json(template)
+}
+
+/// Shorthand for `node().filter(pred)`
+#[inline]
+pub fn filter<P>(pred: P) -> Filter<Node,P>
+                 where P: Fn(&Json) -> bool {
+    node().filter(pred)
+}
+
+/// Shorthand for `node().filter_path(pred)`
+#[inline]
+pub fn filter_path<P>(pred: P) -> FilterPath<Node,P>
+                      where P: for<'a,'b> Fn(&JsonPath<'a,'b>) -> bool {
+    node().filter_path(pred)
+}
+
+/// Shorthand for `node().flat_map(f)`
+#[inline]
+pub fn flat_map<M>(f: M) -> FlatMap<Node,M>
+                   where M: for<'a> Fn(&'a Json) -> Vec<&'a Json> {
+    node().flat_map(f)
+}
+
+/// Shorthand for `node().intersect(left, right)`
+#[inline]
+pub fn intersect<T1:Selector,T2:Selector>(left: T1, right: T2) -> Intersect<Node,T1,T2> {
+    node().intersect(left, right)
+}
+
+/// Shorthand for `node().union(left, right)`
+#[inline]
+pub fn union<T1:Selector,T2:Selector>(left: T1, right: T2) -> Union<Node,T1,T2> {
+    node().union(left, right)
+}
+
+/// Shorthand for `node().diff(left, right)`
+#[inline]
+pub fn diff<T1:Selector,T2:Selector>(left: T1, right: T2) -> Diff<Node,T1,T2> {
+    node().diff(left, right)
+}
+
+/// Shorthand for `node().and(left, right)`
+#[inline]
+pub fn and<T1:Selector,T2:Selector>(left: T1, right: T2) -> AndSel<Node,T1,T2> {
+    node().and(left, right)
+}
+
+/// Shorthand for `node().or(left, right)`
+#[inline]
+pub fn or<T1:Selector,T2:Selector>(left: T1, right: T2) -> OrSel<Node,T1,T2> {
+    node().or(left, right)
+}
+
+/// Shorthand for `node().union_all(list)`
+#[inline]
+pub fn union_all<L:Selectors>(list: L) -> UnionAll<Node,L> {
+    node().union_all(list)
 }
 
-/// Shorthand for `node().key(name)`
+/// Shorthand for `node().any_of(list)`
 #[inline]
-pub fn key<'a>(name: &'a str) -> Key<'a, Node> {
-    node().key(name)
+pub fn any_of<L:Selectors>(list: L) -> AnyOf<Node,L> {
+    node().any_of(list)
 }
 
-/// Shorthand for `node().wherein(filter)`
+/// Shorthand for `node().all_of(list)`
 #[inline]
-pub fn wherein<T:Selector>(filter: T) -> Wherein<Node,T> {
-    node().wherein(filter)
+pub fn all_of<L:Selectors>(list: L) -> AllOf<Node,L> {
+    node().all_of(list)
 }
 
-/// Shorthand for `node().intersect(left, right)`
+/// Shorthand for `node().if_then_else(cond, then, otherwise)`
 #[inline]
-pub fn intersect<T1:Selector,T2:Selector>(left: T1, right: T2) -> Intersect<Node,T1,T2> {
-    node().intersect(left, right)
+pub fn if_then_else<C:Selector,T:Selector,E:Selector>(cond: C, then: T, otherwise: E)
+                                                     -> IfThenElse<Node,C,T,E> {
+    node().if_then_else(cond, then, otherwise)
 }
 
-/// Shorthand for `node().union(left, right)`
+/// Shorthand for `node().coalesce(list)`
 #[inline]
-pub fn union<T1:Selector,T2:Selector>(left: T1, right: T2) -> Union<Node,T1,T2> {
-    node().union(left, right)
+pub fn coalesce<L:Selectors>(list: L) -> Coalesce<Node,L> {
+    node().coalesce(list)
 }
 
-/// Shorthand for `node().diff(left, right)`
+/// Shorthand for `node().union_values(left, right)`
 #[inline]
-pub fn diff<T1:Selector,T2:Selector>(left: T1, right: T2) -> Diff<Node,T1,T2> {
-    node().diff(left, right)
+pub fn union_values<T1:Selector,T2:Selector>(left: T1, right: T2) -> UnionValues<Node,T1,T2> {
+    node().union_values(left, right)
 }
 
-/// Shorthand for `node().and(left, right)`
+/// Shorthand for `node().intersect_values(left, right)`
 #[inline]
-pub fn and<T1:Selector,T2:Selector>(left: T1, right: T2) -> AndSel<Node,T1,T2> {
-    node().and(left, right)
+pub fn intersect_values<T1:Selector,T2:Selector>(left: T1, right: T2) -> IntersectValues<Node,T1,T2> {
+    node().intersect_values(left, right)
 }
 
-/// Shorthand for `node().or(left, right)`
+/// Shorthand for `node().diff_values(left, right)`
 #[inline]
-pub fn or<T1:Selector,T2:Selector>(left: T1, right: T2) -> OrSel<Node,T1,T2> {
-    node().or(left, right)
+pub fn diff_values<T1:Selector,T2:Selector>(left: T1, right: T2) -> DiffValues<Node,T1,T2> {
+    node().diff_values(left, right)
 }
 
 #[cfg(test)]
 mod test {
-    use super::{child,wherein,Selector,JsonExt};
+    use super::{at,node,root,child,uint64,equals_json,ToPlan,Key,Named,Node,StringEquals,ContainsJson,coalesce,if_then_else,all_of,any_of,union_all,union,and,string,float64,compare,contains_json,flat_map,Step,equals_sel,lit,Cmp,Expr,descend,descend_breadth,descend_max,descend_until,descend_prune,key,list,object,wherein,intersect_values,union_values,Selector,JsonExt,Limits,Error,DEFAULT_MAX_DEPTH};
     use serialize::json;
 
     fn from_str(s: &str) -> Option<json::Json> {
@@ -1085,4 +5167,622 @@ mod test {
         let matches = json.query(child().null());
         assert_eq!(matches.len(), 2);
     }
+
+    #[test]
+    fn distinct_values() {
+        let json = from_str(r#"[1,2,[1],2,1.0,[1.0],-0.0,0.0]"#).unwrap();
+
+        let matches = json.query(child().distinct());
+        assert_eq!(matches.len(), 4);
+        assert_eq!(json.query(union_values(at(0), at(4))).len(), 1);
+    }
+
+    #[test]
+    fn distinct_large_integers() {
+        // 2^53 + 1 rounds to 2^53 as a float
+        let json = from_str(r#"[9007199254740993, 9007199254740992, 9007199254740992.0,
+                                -9007199254740993, -9007199254740992.0]"#).unwrap();
+
+        assert_eq!(json.query(child().distinct()).len(), 4);
+        assert_eq!(json.query(union_values(at(0), at(2))).len(), 2);
+        assert_eq!(json.query(union_values(at(1), at(2))).len(), 1);
+        assert_eq!(json.query(intersect_values(at(0), at(2))).len(), 0);
+        assert_eq!(json.query(intersect_values(at(1), at(2))).len(), 1);
+        assert_eq!(json.query(intersect_values(at(3), at(4))).len(), 0);
+        assert_eq!(json.query(compare(at(0), Cmp::Gt, at(2))).len(), 1);
+        assert_eq!(json.query(compare(at(3), Cmp::Lt, at(4))).len(), 1);
+    }
+
+    #[test]
+    fn intersect_values_unique() {
+        let json = from_str(r#"{"a": {"tags": ["x","y","z","y"]},
+                                "b": {"tags": ["y","w","x"]}}"#).unwrap();
+
+        let matches = json.query(
+            intersect_values(key("a").key("tags").child(),
+                             key("b").key("tags").child()));
+        assert_eq!(matches.len(), 2);
+    }
+
+    fn nested(depth: usize) -> json::Json {
+        let s = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        from_str(&s).unwrap()
+    }
+
+    #[test]
+    fn descend_deep() {
+        let json = nested(2000);
+
+        let matches = json.try_query_with(descend().list().ascend(),
+                                          &Limits::new().max_depth(2000));
+        assert_eq!(matches.map(|m| m.len()), Ok(1999));
+    }
+
+    #[test]
+    fn descend_depth_limit() {
+        let json = nested(100);
+
+        let result = json.try_query_with(descend(), &Limits::new().max_depth(10));
+        assert_eq!(result, Err(Error::DepthLimit(10)));
+
+        let matches = json.query(child().descend());
+        assert_eq!(matches.len(), 98);
+
+        let json = nested(2000);
+        assert_eq!(json.query(descend()).len(), 1999);
+        assert_eq!(json.try_query(descend()), Err(Error::DepthLimit(DEFAULT_MAX_DEPTH)));
+    }
+
+    #[test]
+    fn descend_bounded() {
+        let json = from_str(r#"{"a": {"skip": [1, 2], "b": [3, {"c": 4}]},
+                                "d": [5]}"#).unwrap();
+
+        let matches = json.query(descend_max(2));
+        assert_eq!(matches.len(), 5);
+
+        let matches = json.query(descend_max(0));
+        assert_eq!(matches.len(), 0);
+
+        let matches = json.query(union(node(), key("a")).descend_max(2));
+        assert_eq!(matches.len(), 9);
+
+        let matches = json.query(child().descend_or_self());
+        assert_eq!(matches.len(), 10);
+
+        let matches = json.query(descend_until(list()));
+        assert_eq!(matches.len(), 4);
+
+        let matches = json.query(descend_prune(object().key("skip")));
+        assert_eq!(matches.len(), 2);
+
+        let matches = json.query(descend().descend_or_self());
+        assert_eq!(matches.len(), 10);
+    }
+
+    #[test]
+    fn descend_breadth_order() {
+        let json = from_str(r#"[[[{"id": 1}]], {"x": {"id": 2}}, [{"id": 3}]]"#).unwrap();
+
+        let matches = json.query(descend_breadth().key("id"));
+        assert_eq!(matches, vec![&json::Json::U64(2), &json::Json::U64(3), &json::Json::U64(1)]);
+
+        let matches = json.query(descend_breadth().key("id").parent().ascend());
+        assert_eq!(matches.len(), 5);
+
+        let result = json.try_query_with(descend_breadth(), &Limits::new().max_depth(2));
+        assert_eq!(result, Err(Error::DepthLimit(2)));
+    }
+
+    #[test]
+    fn sibling_axes() {
+        let json = from_str(r#"[{"type": "info", "n": 1},
+                                {"type": "error", "n": 2},
+                                {"type": "info", "n": 3},
+                                {"type": "error", "n": 4}]"#).unwrap();
+        let errors = || child().wherein(key("type").string().equals("error"));
+
+        let matches = json.query(errors().next_sibling().key("n"));
+        assert_eq!(matches, vec![&json::Json::U64(3)]);
+
+        let matches = json.query(errors().prev_sibling().key("n"));
+        assert_eq!(matches, vec![&json::Json::U64(1), &json::Json::U64(3)]);
+
+        let matches = json.query(errors().following_siblings());
+        assert_eq!(matches.len(), 2);
+
+        let matches = json.query(errors().preceding_siblings());
+        assert_eq!(matches.len(), 3);
+
+        let matches = json.query(at(0).key("n").siblings());
+        assert_eq!(matches, vec![&json::Json::String("info".to_string())]);
+
+        let matches = json.query(at(0).key("n").prev_sibling());
+        assert_eq!(matches.len(), 0);
+
+        let matches = json.query(child().and(node(), node()).siblings());
+        assert_eq!(matches.len(), 0);
+
+        let json = from_str(r#"{"x": 1, "y": 2}"#).unwrap();
+        let matches = json.query(and(key("x"), key("y")));
+        assert_eq!(matches.len(), 1);
+        assert_eq!(json.query(and(key("x"), key("y")).named("0")).len(), 0);
+        assert_eq!(json.query(and(key("x"), key("y")).first()).len(), 0);
+        assert_eq!(json.query(and(key("x"), key("y")).at_depth(1)).len(), 0);
+    }
+
+    #[test]
+    fn position_predicates() {
+        let json = from_str(r#"[[1, 2, 3], {"a": 4, "b": [5, []]}, 6]"#).unwrap();
+        let u = |n| json::Json::U64(n);
+
+        let matches = json.query(descend().first());
+        assert_eq!(matches.len(), 3);
+
+        let matches = json.query(descend().last().uint64());
+        assert_eq!(matches, vec![&u(3), &u(6)]);
+
+        let matches = json.query(child().child().nth(1));
+        assert_eq!(matches, vec![&u(2)]);
+
+        let matches = json.query(at(0).child().even());
+        assert_eq!(matches, vec![&u(1), &u(3)]);
+
+        let matches = json.query(at(0).child().odd());
+        assert_eq!(matches, vec![&u(2)]);
+
+        let matches = json.query(descend().at_depth(2).uint64());
+        assert_eq!(matches, vec![&u(1), &u(2), &u(3), &u(4)]);
+
+        let matches = json.query(descend().leaf());
+        assert_eq!(matches.len(), 7);
+
+        let matches = json.query(at(1).key("a").first());
+        assert_eq!(matches.len(), 0);
+    }
+
+    #[test]
+    fn named_keys() {
+        let json = from_str(r#"{"user": {"password": "a", "name": "b"},
+                                "db": {"password": "c", "user_id": 7},
+                                "list": [{"password": "d"}, 8]}"#).unwrap();
+
+        let matches = json.query(descend().named("password"));
+        assert_eq!(matches.len(), 3);
+
+        let matches = json.query(descend().named("user*"));
+        assert_eq!(matches.len(), 2);
+
+        let matches = json.query(descend().named("*a*e"));
+        assert_eq!(matches.len(), 1);
+
+        let matches = json.query(key("list").child().named("1"));
+        assert_eq!(matches, vec![&json::Json::U64(8)]);
+
+        let matches = json.query(descend().named("pass?ord").parent().named("?"));
+        assert_eq!(matches.len(), 1);
+    }
+
+    #[test]
+    fn root_in_filter() {
+        let json = from_str(r#"{"strict": true,
+                                "items": [{"a": 1}, {"b": [2]}]}"#).unwrap();
+
+        let matches = json.query(descend().root());
+        assert_eq!(matches, vec![&json]);
+
+        let matches = json.query(
+            key("items").child().wherein(root().key("strict").boolean().equals(true)));
+        assert_eq!(matches.len(), 2);
+
+        let matches = json.query(
+            key("items").child().wherein(root().key("strict").boolean().equals(false)));
+        assert_eq!(matches.len(), 0);
+    }
+
+    #[test]
+    fn compare_selected() {
+        let json = from_str(r#"{"current_user": "bob",
+                                "quotas": [{"owner": "bob", "used": 5, "limit": 10},
+                                           {"owner": "amy", "used": 12, "limit": 10.5},
+                                           {"owner": "bob", "used": -1, "limit": 0}]}"#).unwrap();
+        let quotas = || key("quotas").child();
+
+        let matches = json.query(quotas().compare(key("used"), Cmp::Gt, key("limit")));
+        assert_eq!(matches.len(), 1);
+
+        let matches = json.query(quotas().compare(key("used"), Cmp::Le, key("limit")));
+        assert_eq!(matches.len(), 2);
+
+        let matches = json.query(
+            quotas().wherein(equals_sel(key("owner"), root().key("current_user"))));
+        assert_eq!(matches.len(), 2);
+
+        let matches = json.query(quotas().wherein(compare(key("owner"), Cmp::Ne, key("used"))));
+        assert_eq!(matches.len(), 3);
+
+        let matches = json.query(quotas().compare(key("owner"), Cmp::Lt, key("used")));
+        assert_eq!(matches.len(), 0);
+    }
+
+    #[test]
+    fn expressions() {
+        let json = from_str(r#"[{"name": "Fast", "bytes": 5000000, "duration": 2},
+                                {"name": "slow", "bytes": 3, "duration": 2},
+                                {"name": "Odd", "bytes": -7.5, "duration": 0}]"#).unwrap();
+
+        let matches = json.query(
+            child().compare(key("bytes").div(key("duration")), Cmp::Gt, lit(&1e6)));
+        assert_eq!(matches.len(), 1);
+
+        let values = json.eval(child().map(key("bytes").div(key("duration"))));
+        assert_eq!(values, vec![json::Json::U64(2500000), json::Json::F64(1.5)]);
+
+        let values = json.eval(child().map(key("bytes").sub(lit(&10)).mul(lit(&2))));
+        assert_eq!(values, vec![json::Json::U64(9999980), json::Json::I64(-14),
+                                json::Json::F64(-35.0)]);
+
+        let values = json.eval(child().key("name").map(node().upper().concat(lit("!"))));
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[1], json::Json::String("SLOW!".to_string()));
+
+        let matches = json.query(child().compare(key("name"), Cmp::Ne, key("name").lower()));
+        assert_eq!(matches.len(), 2);
+
+        let values = json.eval(lit("12").to_number().add(at(0).key("duration")).length());
+        assert_eq!(values.len(), 0);
+        let values = json.eval(lit(" 12 ").to_number().add(at(0).key("duration")).to_text());
+        assert_eq!(values, vec![json::Json::String("14".to_string())]);
+
+        let values = json.eval(child().map(key("name").length()));
+        assert_eq!(values, vec![json::Json::U64(4), json::Json::U64(4), json::Json::U64(3)]);
+
+        let json = from_str(r#"{"x": 18446744073709551615, "y": -9223372036854775808}"#).unwrap();
+        let max = u64::MAX as f64;
+        assert_eq!(json.eval(key("x").mul(key("x"))), vec![json::Json::F64(max * max)]);
+        assert_eq!(json.eval(key("y").mul(key("x"))), vec![json::Json::F64(-max * max / 2.0)]);
+        assert_eq!(json.eval(key("x").add(key("x"))), vec![json::Json::F64(2.0 * max)]);
+    }
+
+    #[test]
+    fn length_predicates() {
+        let json = from_str(r#"{"errors": [], "warnings": ["a", "b"],
+                                "meta": {"x": 1, "y": 2, "z": 3},
+                                "name": "héllo"}"#).unwrap();
+
+        let matches = json.query(child().list().is_empty());
+        assert_eq!(matches.len(), 1);
+
+        let matches = json.query(child().list().len_gt(0));
+        assert_eq!(matches.len(), 1);
+
+        let matches = json.query(child().object().len_gt(2));
+        assert_eq!(matches.len(), 1);
+
+        let matches = json.query(child().string().len_eq(5));
+        assert_eq!(matches.len(), 1);
+
+        let matches = json.query(descend().string().len_between(1, 5));
+        assert_eq!(matches.len(), 3);
+
+        let matches = json.query(wherein(key("errors").list().len_gt(0)));
+        assert_eq!(matches.len(), 0);
+    }
+
+    #[test]
+    fn json_matching() {
+        let json = from_str(r#"[{"kind": "pod", "meta": {"labels": ["a", "b"], "n": 1}},
+                                {"kind": "pod", "meta": {"labels": ["b"], "n": 1.0}},
+                                {"kind": "svc", "meta": {"labels": ["a"], "n": 2}}]"#).unwrap();
+
+        let value = from_str(r#"{"labels": ["b"], "n": 1}"#).unwrap();
+        let matches = json.query(child().key("meta").equals_json(&value));
+        assert_eq!(matches.len(), 1);
+
+        let template = from_str(r#"{"kind": "pod", "meta": {"labels": ["b"]}}"#).unwrap();
+        let matches = json.query(child().contains_json(&template));
+        assert_eq!(matches.len(), 2);
+
+        let template = from_str(r#"{"meta": {"labels": ["a"], "n": 1}}"#).unwrap();
+        let matches = json.query(child().contains_json(&template));
+        assert_eq!(matches.len(), 1);
+
+        let template = from_str(r#"[{"kind": "svc"}, {"meta": {"n": 1}}]"#).unwrap();
+        let matches = json.query(contains_json(&template));
+        assert_eq!(matches.len(), 1);
+
+        let template = from_str(r#"{"kind": "job"}"#).unwrap();
+        let matches = json.query(child().contains_json(&template));
+        assert_eq!(matches.len(), 0);
+    }
+
+    #[test]
+    fn closures() {
+        let json = from_str(r#"{"a": [1, 2, 30], "b": {"id": 7, "c": {"id": 8}}}"#).unwrap();
+
+        let matches = json.query(descend().filter(|j| j.as_u64().is_some_and(|n| n % 2 == 0)));
+        assert_eq!(matches.len(), 3);
+
+        let matches = json.query(descend().filter_path(|p| p.depth() == 2 && p.step() == Some(Step::Key("id"))));
+        assert_eq!(matches, vec![&json::Json::U64(7)]);
+
+        let matches = json.query(
+            flat_map(|j| j.search("id").into_iter().collect()).parent().filter_path(|p| p.depth() == 1));
+        assert_eq!(matches.len(), 1);
+
+        let matches = json.query(flat_map(|j| j.find_path(&["b", "c", "id"]).into_iter().collect()).ascend());
+        assert_eq!(matches.len(), 3);
+    }
+
+    #[test]
+    fn flat_map_deep_results() {
+        // 100 lists of 100 numbers, each found below the root once
+        let json = from_str(&format!("[{}]", vec![format!("[{}]", vec!["1"; 100].join(","));
+                                                  100].join(","))).unwrap();
+        fn grandchildren(j: &json::Json) -> Vec<&json::Json> {
+            j.as_array().into_iter().flatten().flat_map(|c| c.as_array().into_iter().flatten()).collect()
+        }
+
+        let matches = json.try_query_with(flat_map(grandchildren).filter_path(|p| p.depth() == 2),
+                                          &Limits::new().max_nodes(20000));
+        assert_eq!(matches.map(|m| m.len()), Ok(10000));
+        let plan = flat_map(grandchildren).parent().to_plan().compile();
+        assert_eq!(json.try_query_with(plan, &Limits::new().max_nodes(20000)).map(|m| m.len()), Ok(100));
+    }
+
+    #[test]
+    fn float_predicates() {
+        let json = json::Json::Array(vec![
+            json::Json::F64(0.1 + 0.2), json::Json::F64(f64::NAN), json::Json::F64(f64::INFINITY),
+            json::Json::F64(-0.0), json::Json::F64(3.0), json::Json::F64(1e-300)]);
+
+        assert_eq!(json.query(child().float64().equals(0.3)).len(), 0);
+        assert_eq!(json.query(child().float64().approx_equals(0.3, 0.0, 1e-12)).len(), 1);
+        assert_eq!(json.query(child().float64().approx_equals(0.0, 1e-200, 0.0)).len(), 2);
+        assert_eq!(json.query(child().float64().ulps_equals(0.3, 1)).len(), 1);
+        assert_eq!(json.query(child().float64().ulps_equals(0.0, 0)).len(), 1);
+        assert_eq!(json.query(child().float64().ulps_equals(f64::INFINITY, 0)).len(), 1);
+        assert_eq!(json.query(child().float64().is_nan()).len(), 1);
+        assert_eq!(json.query(child().float64().is_finite()).len(), 4);
+        assert_eq!(json.query(child().float64().is_integer()).len(), 2);
+        assert_eq!(json.query(float64().is_finite()).len(), 0);
+    }
+
+    #[test]
+    fn variadic() {
+        let json = from_str(r#"[{"status": "ok", "code": 1},
+                                {"status": "failed", "code": 2},
+                                {"status": "timeout"},
+                                {"status": "cancelled", "code": 4}]"#).unwrap();
+        let statuses = ["failed", "timeout", "cancelled"];
+
+        let any: Vec<_> = statuses.iter().map(|&s| key("status").string().equals(s)).collect();
+        let matches = json.query(child().wherein(any_of(&any)));
+        assert_eq!(matches.len(), 3);
+
+        let matches = json.query(child().wherein(any_of(any)).wherein(key("code")));
+        assert_eq!(matches.len(), 2);
+
+        let matches = json.query(child().wherein(all_of((key("code").uint64(),
+                                                         key("status").string().equals("failed"),
+                                                         key("status")))));
+        assert_eq!(matches.len(), 1);
+
+        let matches = json.query(child().union_all([key("status"), key("code"), key("status")]));
+        assert_eq!(matches.len(), 7);
+
+        let matches = json.query(union_all((at(0), at(0).parent().child(), string())));
+        assert_eq!(matches.len(), 4);
+    }
+
+    #[test]
+    fn conditional() {
+        let json = from_str(r#"[{"hostname": "a", "host": "x"},
+                                {"host": "b"},
+                                {"node": {"name": "c"}},
+                                {"version": 2, "host": "d", "node": {"name": "e"}},
+                                {}]"#).unwrap();
+        let s = |s: &str| json::Json::String(s.to_string());
+
+        let matches = json.query(child().coalesce((key("hostname"),
+                                                   key("host"),
+                                                   key("node").key("name"))));
+        assert_eq!(matches, vec![&s("a"), &s("b"), &s("c"), &s("d")]);
+
+        let matches = json.query(child().if_then_else(key("version").uint64().equals(2),
+                                                      key("node").key("name"),
+                                                      key("host")));
+        assert_eq!(matches, vec![&s("x"), &s("b"), &s("e")]);
+
+        let matches = json.query(coalesce(vec![key("missing"), key("other")]));
+        assert_eq!(matches.len(), 0);
+
+        let matches = json.query(if_then_else(at(4).child(), node(), at(4).parent()));
+        assert_eq!(matches, vec![&json]);
+    }
+
+    fn owned_query(field: &str, value: &str, template: &str)
+                   -> Named<'static, ContainsJson<'static, StringEquals<'static, Key<'static, Node>>>> {
+        let template = from_str(template).unwrap();
+        key(field.to_string()).string().equals(value.to_string())
+            .contains_json(template).named(format!("{}*", field))
+    }
+
+    #[test]
+    fn owned_selectors() {
+        let json = from_str(r#"{"host": "a"}"#).unwrap();
+        let query = owned_query("host", "a", r#""a""#);
+
+        let matches = json.query(query);
+        assert_eq!(matches.len(), 1);
+
+        let query = owned_query("host", "a", r#""b""#);
+        let matches = std::thread::spawn(move || {
+            let json = from_str(r#"{"host": "a"}"#).unwrap();
+            json.query(query).len()
+        }).join().unwrap();
+        assert_eq!(matches, 0);
+    }
+
+    #[test]
+    fn selector_traits() {
+        fn shared<S:Selector+Clone+Send+Sync+'static>(s: &S) -> S {
+            s.clone()
+        }
+
+        let json = from_str(r#"[{"a": 1}, {"a": 2}]"#).unwrap();
+        let query = child().wherein(key("a".to_string()).uint64().equals(2));
+
+        assert_eq!(query, shared(&query));
+        assert_eq!(json.query(shared(&query)).len(), 1);
+        assert!(query != child().wherein(key("a").uint64().equals(1)));
+        assert!(format!("{:?}", query).contains("U64Equals"));
+
+        let query = child().filter(|j| j.is_object());
+        assert_eq!(json.query(shared(&query)).len(), 2);
+        assert!(format!("{:?}", query).starts_with("Filter"));
+    }
+
+    #[test]
+    fn display() {
+        let query = list().child().wherein(
+            key("foo").list().child().or(
+                string().equals("Hello, world!"),
+                uint64().equals(42)));
+        assert_eq!(query.to_string(),
+                   r#"list().child().wherein(key("foo").list().child().or(string().equals("Hello, world!"), uint64().equals(42)))"#);
+
+        assert_eq!(node().to_string(), "node()");
+        assert_eq!(descend().named("a*").string().len_gt(3).to_string(),
+                   r#"descend().named("a*").string().len_gt(3)"#);
+        assert_eq!(child().filter(|j| j.is_null()).distinct().to_string(),
+                   "child().filter(..).distinct()");
+        assert_eq!(union_all((key("a"),)).to_string(), r#"union_all((key("a"),))"#);
+        assert_eq!(any_of([at(0), at(1)]).to_string(), "any_of([at(0), at(1)])");
+        assert_eq!(compare(key("a").add(lit(&1)), Cmp::Gt, key("b").length()).to_string(),
+                   r#"compare(key("a").add(lit(&1i64)), Cmp::Gt, key("b").length())"#);
+        assert_eq!(contains_json(from_str(r#"{"a":[1]}"#).unwrap()).to_string(),
+                   r##"contains_json(Json::from_str(r#"{"a":[1]}"#).unwrap())"##);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        use serialize::json::Json;
+
+        // Each selector is written as the expression building it
+        macro_rules! round_trip {
+            ($($sel:expr),+) => {
+                $(
+                    assert_eq!($sel.to_string(), stringify!($sel));
+                    assert_eq!($sel.to_plan().to_string(), stringify!($sel));
+                )+
+            }
+        }
+
+        round_trip!(
+            node(),
+            node().distinct(),
+            child().key("a\"b").at(2).distinct(),
+            object().len_eq(2),
+            string().len_between(1, 3),
+            float64().equals(-1.5),
+            float64().equals(f64::NAN),
+            float64().approx_equals(f64::INFINITY, 1e-9, 0.0),
+            equals_json(Json::U64(1)),
+            equals_json(Json::F64(f64::NEG_INFINITY)),
+            equals_json(Json::from_str(r#"{"a":[1,"b"]}"#).unwrap()),
+            contains_json(Json::from_str(r##"{"a\"#":null}"##).unwrap()),
+            compare(key("a").add(lit(&1u64)), Cmp::Gt, lit(&-2i64)),
+            compare(child().map(key("b")).length(), Cmp::Eq, lit("x")),
+            compare(node().mul(lit(&1.5f64)), Cmp::Lt, lit(&Json::Null)),
+            compare(node(), Cmp::Ne, lit(&Json::F64(f64::NAN))),
+            key("a").union(at(0), node().distinct()),
+            union_all([key("a"), key("b")]),
+            any_of([at(0), at(1)]));
+    }
+
+    #[test]
+    fn trace() {
+        let json = from_str(r#"[{"foo": [1]}, {"foo": "x"}, {"bar": 2}]"#).unwrap();
+        let (matches, trace) = json.trace(list().child().wherein(key("foo").list()));
+        assert_eq!(matches.len(), 1);
+        assert_eq!((trace.input(), trace.output()), (1, 1));
+
+        let wherein = &trace.children()[0];
+        assert_eq!(wherein.name(), "Wherein");
+        assert_eq!((wherein.input(), wherein.output()), (3, 1));
+        assert_eq!(wherein.rejected(), ["$[1]", "$[2]"]);
+
+        let inner = &wherein.children()[0];
+        assert_eq!(inner.name(), "Child");
+        assert_eq!((inner.input(), inner.output()), (1, 3));
+
+        let filter = &wherein.children()[1];
+        assert_eq!(filter.name(), "ListSel");
+        assert_eq!((filter.input(), filter.output()), (2, 1));
+        assert_eq!(filter.rejected(), ["$[1].foo"]);
+        assert_eq!(filter.children()[0].rejected(), ["$[2]"]);
+
+        let (_, trace) = json.trace(child().and(key("foo"), key("baz")));
+        assert_eq!(trace.children()[0].rejected(), ["$"]);
+    }
+
+    #[test]
+    fn profile() {
+        let json = from_str(r#"[[1, 2], [3], {"a": [4]}]"#).unwrap();
+        let (matches, profile) = json.profile(descend().uint64().parent());
+        assert_eq!(matches.len(), 3);
+
+        let descend = profile.selector("Descend").unwrap();
+        assert_eq!(descend.calls(), 1);
+        assert_eq!(descend.nodes_visited(), 8);
+        assert_eq!(descend.sets_created(), 1);
+        assert_eq!(descend.set_insertions(), 5);
+
+        let parent = profile.selector("Parent").unwrap();
+        assert_eq!(parent.sets_created(), 1);
+        assert_eq!(parent.set_insertions(), 3);
+        assert_eq!(profile.nodes_visited(), 8);
+        assert_eq!(profile.selector("U64Sel").unwrap().calls(), 1);
+        assert!(profile.selector("Child").is_none());
+        assert!(profile.to_string().contains("Descend"));
+    }
+
+    #[test]
+    fn budget_limits() {
+        let json = from_str(r#"[[1, 2, [3]], {"a": {"b": [4, 5]}}]"#).unwrap();
+
+        let query = descend().descend().ascend();
+        assert_eq!(json.try_query(query.clone()).unwrap().len(), 6);
+        assert_eq!(json.try_query_with(query, &Limits::new().max_nodes(12)),
+                   Err(Error::NodeLimit(12)));
+
+        assert_eq!(json.try_query_with(descend(), &Limits::new().max_results(3)),
+                   Err(Error::ResultLimit(3)));
+        assert_eq!(json.try_query_with(descend(), &Limits::new().max_results(10)).unwrap().len(), 10);
+
+        let query = descend().wherein(wherein(wherein(node())));
+        assert!(json.try_query_with(query.clone(), &Limits::new().max_filter_depth(3)).is_ok());
+        assert_eq!(json.try_query_with(query, &Limits::new().max_filter_depth(2)),
+                   Err(Error::FilterDepthLimit(2)));
+
+        let bytes = 4 * ::std::mem::size_of::<usize>();
+        let limits = Limits::new().max_set_memory(bytes);
+        assert_eq!(json.try_query_with(descend(), &limits), Err(Error::SetMemoryLimit(bytes)));
+        assert!(json.try_query_with(child().union(node(), node()), &limits).is_ok());
+
+        // Expressions pair every value of one operand with every
+        // value of the other
+        let list = json::Json::Array((0..100).map(json::Json::U64).collect());
+        let query = compare(child().add(child()).add(child()).add(child()), Cmp::Eq, lit(&-1));
+        let limits = Limits::new().max_nodes(1000);
+        assert_eq!(list.try_query_with(query.clone(), &limits), Err(Error::NodeLimit(1000)));
+        assert_eq!(list.try_query_with(query.to_plan().compile(), &limits), Err(Error::NodeLimit(1000)));
+        let nested = json::Json::Array(vec![list.clone()]);
+        assert_eq!(nested.try_query_with(equals_json(&nested), &Limits::new().max_nodes(50)),
+                   Err(Error::NodeLimit(50)));
+        let limits = Limits::new().max_set_memory(bytes);
+        assert_eq!(list.try_query_with(compare(node(), Cmp::Eq, child()), &limits),
+                   Err(Error::SetMemoryLimit(bytes)));
+    }
 }
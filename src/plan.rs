@@ -0,0 +1,1457 @@
+//! Introspectable plans of selectors
+//!
+//! Selectors are built from generic structs whose shape is only
+//! known to the compiler.  A `Plan` describes the same query as a
+//! plain sequence of `Op` values which can be inspected, analyzed and
+//! built at runtime.  Any selector can be converted to a plan with
+//! `ToPlan::to_plan`, and a plan is written by `Display` exactly as
+//! the selector it was converted from.
+//!
+//! A plan is itself a selector, which runs the selectors its stages
+//! describe.  `Plan::optimize` rewrites a plan into a cheaper one
+//! selecting the same nodes.
+
+use serialize::json::Json;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::{fmt,ops};
+
+use super::{ArithOp,Axis,Cmp,Expr,F64Test,Func,JsonPath,Position,Program,Selector,identify_stage};
+use super::{Node,ObjectSel,ListSel,StringSel,StringEquals,LenRange,BooleanSel,BooleanEquals};
+use super::{U64Sel,U64Equals,I64Sel,I64Equals,F64Sel,F64Equals,F64Pred,NullSel};
+use super::{At,Key,Child,Parent,RootSel,Sibling,PositionSel,AtDepth,Named,Leaf};
+use super::{Descend,DescendBreadth,DescendMax,DescendOrSelf,DescendUntil,DescendPrune,Ascend};
+use super::{Wherein,EqualsJson,ContainsJson,Compare,Filter,FilterPath,FlatMap};
+use super::{Union,Intersect,Diff,AndSel,OrSel,UnionAll,AnyOf,AllOf,IfThenElse,Coalesce};
+use super::{Lit,Map,Arith,Concat,Apply,Distinct,UnionValues,IntersectValues,DiffValues};
+
+/// Variant of a `Json` node
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// `Json::Object`
+    Object,
+    /// `Json::Array`
+    Array,
+    /// `Json::String`
+    String,
+    /// `Json::Boolean`
+    Boolean,
+    /// `Json::U64`
+    U64,
+    /// `Json::I64`
+    I64,
+    /// `Json::F64`
+    F64,
+    /// `Json::Null`
+    Null
+}
+
+const KINDS: [Kind; 8] = [Kind::Object, Kind::Array, Kind::String, Kind::Boolean,
+                          Kind::U64, Kind::I64, Kind::F64, Kind::Null];
+
+impl Kind {
+    /// Return the kind of node `j`
+    pub fn of(j: &Json) -> Kind {
+        match *j {
+            Json::Object(..) => Kind::Object,
+            Json::Array(..) => Kind::Array,
+            Json::String(..) => Kind::String,
+            Json::Boolean(..) => Kind::Boolean,
+            Json::U64(..) => Kind::U64,
+            Json::I64(..) => Kind::I64,
+            Json::F64(..) => Kind::F64,
+            Json::Null => Kind::Null
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    /// Write the name of the method selecting the kind
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Kind::Object => "object",
+            Kind::Array => "list",
+            Kind::String => "string",
+            Kind::Boolean => "boolean",
+            Kind::U64 => "uint64",
+            Kind::I64 => "int64",
+            Kind::F64 => "float64",
+            Kind::Null => "null"
+        })
+    }
+}
+
+/// Set of `Kind`s
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Kinds(u8);
+
+impl Kinds {
+    /// No kinds
+    pub const NONE: Kinds = Kinds(0);
+    /// All kinds
+    pub const ALL: Kinds = Kinds(0xff);
+    /// Objects and lists
+    pub const CONTAINER: Kinds = Kinds(1 << Kind::Object as u8 | 1 << Kind::Array as u8);
+    /// All kinds of numbers
+    pub const NUMBER: Kinds = Kinds(1 << Kind::U64 as u8 | 1 << Kind::I64 as u8 | 1 << Kind::F64 as u8);
+
+    /// Check whether the set contains `kind`
+    pub fn contains(self, kind: Kind) -> bool {
+        self.0 & 1 << kind as u8 != 0
+    }
+
+    /// Check whether the set is empty
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Check whether every kind in the set is also in `other`
+    pub fn is_subset(self, other: Kinds) -> bool {
+        self.0 & !other.0 == 0
+    }
+
+    /// Iterate over the kinds in the set
+    pub fn iter(self) -> impl Iterator<Item=Kind> {
+        KINDS.iter().cloned().filter(move |&k| self.contains(k))
+    }
+}
+
+impl From<Kind> for Kinds {
+    fn from(kind: Kind) -> Kinds {
+        Kinds(1 << kind as u8)
+    }
+}
+
+impl ops::BitOr for Kinds {
+    type Output = Kinds;
+
+    fn bitor(self, other: Kinds) -> Kinds {
+        Kinds(self.0 | other.0)
+    }
+}
+
+impl ops::BitAnd for Kinds {
+    type Output = Kinds;
+
+    fn bitand(self, other: Kinds) -> Kinds {
+        Kinds(self.0 & other.0)
+    }
+}
+
+impl fmt::Debug for Kinds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl fmt::Display for Kinds {
+    /// Write the kinds as a list such as `object or list`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("nothing")
+        }
+        if *self == Kinds::ALL {
+            return f.write_str("anything")
+        }
+        let count = self.iter().count();
+        for (i, k) in self.iter().enumerate() {
+            if i + 1 == count && i > 0 {
+                f.write_str(" or ")?;
+            } else if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", k)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shared closure in a plan
+///
+/// Closures can't be inspected, so two closures are equal only if
+/// they are the same shared value.
+pub struct Closure<F:?Sized>(Arc<F>);
+
+/// Closure given to `filter`
+pub type NodePredicate = dyn Fn(&Json) -> bool + Send + Sync;
+
+/// Closure given to `filter_path`
+pub type PathPredicate = dyn for<'a,'b> Fn(&JsonPath<'a,'b>) -> bool + Send + Sync;
+
+/// Closure given to `flat_map`
+pub type NodeMapper = dyn for<'j> Fn(&'j Json) -> Vec<&'j Json> + Send + Sync;
+
+impl<F:?Sized> Closure<F> {
+    /// Wrap a shared closure
+    pub fn new(f: Arc<F>) -> Closure<F> {
+        Closure(f)
+    }
+}
+
+impl<F:?Sized> Clone for Closure<F> {
+    fn clone(&self) -> Closure<F> {
+        Closure(self.0.clone())
+    }
+}
+
+impl<F:?Sized> fmt::Debug for Closure<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Closure(..)")
+    }
+}
+
+impl<F:?Sized> PartialEq for Closure<F> {
+    fn eq(&self, other: &Closure<F>) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<F:?Sized> ops::Deref for Closure<F> {
+    type Target = F;
+
+    fn deref(&self) -> &F {
+        &self.0
+    }
+}
+
+/// Stage of a plan
+///
+/// Each variant corresponds to the selector method of the same name,
+/// applied to the nodes selected by the stages before it.  Stages
+/// which combine several selectors, such as `Union`, hold a `Plan`
+/// for each.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    /// `object()`, `list()`, `string()` and so on
+    Is(Kind),
+    /// `string().equals(comp)`
+    StringEquals(String),
+    /// `boolean().equals(comp)`
+    BooleanEquals(bool),
+    /// `uint64().equals(comp)`
+    U64Equals(u64),
+    /// `int64().equals(comp)`
+    I64Equals(i64),
+    /// `float64().equals(comp)`
+    F64Equals(f64),
+    /// `approx_equals`, `is_nan` and the other tests on a
+    /// `Json::F64` node
+    F64Test(F64Test),
+    /// `len_between(min, max)` and the other length tests
+    LenRange(usize, usize),
+    /// `at(index)`
+    At(usize),
+    /// `key(name)`
+    Key(String),
+    /// `child()`
+    Child,
+    /// `parent()`
+    Parent,
+    /// `root()`
+    Root,
+    /// `next_sibling()` and the other sibling axes
+    Sibling(Axis),
+    /// `first()`, `nth(index)` and the other positions
+    Position(Position),
+    /// `at_depth(depth)`
+    AtDepth(usize),
+    /// `named(pattern)`
+    Named(String),
+    /// `leaf()`
+    Leaf,
+    /// `descend()`
+    Descend,
+    /// `descend_breadth()`
+    DescendBreadth,
+    /// `descend_max(depth)`
+    DescendMax(usize),
+    /// `descend_or_self()`
+    DescendOrSelf,
+    /// `descend_until(filter)`
+    DescendUntil(Plan),
+    /// `descend_prune(filter)`
+    DescendPrune(Plan),
+    /// `ascend()`
+    Ascend,
+    /// `wherein(filter)`
+    Wherein(Plan),
+    /// `equals_json(value)`
+    EqualsJson(Json),
+    /// `contains_json(template)`
+    ContainsJson(Json),
+    /// `compare(left, op, right)`
+    Compare(ExprPlan, Cmp, ExprPlan),
+    /// `filter(pred)`
+    Filter(Closure<NodePredicate>),
+    /// `filter_path(pred)`
+    FilterPath(Closure<PathPredicate>),
+    /// `flat_map(f)`
+    FlatMap(Closure<NodeMapper>),
+    /// `union(left, right)`
+    Union(Plan, Plan),
+    /// `intersect(left, right)`
+    Intersect(Plan, Plan),
+    /// `diff(left, right)`
+    Diff(Plan, Plan),
+    /// `and(left, right)`
+    And(Plan, Plan),
+    /// `or(left, right)`
+    Or(Plan, Plan),
+    /// `union_all(list)`
+    UnionAll(Vec<Plan>),
+    /// `any_of(list)`
+    AnyOf(Vec<Plan>),
+    /// `all_of(list)`
+    AllOf(Vec<Plan>),
+    /// `if_then_else(cond, then, otherwise)`
+    IfThenElse(Plan, Plan, Plan),
+    /// `coalesce(list)`
+    Coalesce(Vec<Plan>),
+    /// `distinct()`
+    Distinct,
+    /// `union_values(left, right)`
+    UnionValues(Plan, Plan),
+    /// `intersect_values(left, right)`
+    IntersectValues(Plan, Plan),
+    /// `diff_values(left, right)`
+    DiffValues(Plan, Plan)
+}
+
+impl fmt::Display for Op {
+    /// Write the method call of the stage
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        with_stage(self, Bare, Write(f))
+    }
+}
+
+/// Plan of a selector
+///
+/// A plan is a chain of stages starting at `node()`: each stage runs
+/// on the nodes selected by the stages before it.  The empty plan is
+/// equivalent to `node()`.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Plan {
+    ops: Vec<Op>
+}
+
+impl Plan {
+    /// Create an empty plan
+    pub fn new() -> Plan {
+        Plan { ops: Vec::new() }
+    }
+
+    /// Create a plan running stages `ops` in order
+    pub fn from_ops(ops: Vec<Op>) -> Plan {
+        Plan { ops }
+    }
+
+    /// Append stage `op` to the plan
+    pub fn then(mut self, op: Op) -> Plan {
+        self.ops.push(op);
+        self
+    }
+
+    /// Compile the plan into a `Program`
+    pub fn compile(&self) -> Program {
+        Program::compile(self)
+    }
+
+    /// Return the stages of the plan
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// Consume the plan, returning its stages
+    pub fn into_ops(self) -> Vec<Op> {
+        self.ops
+    }
+
+    /// Analyze the plan
+    ///
+    /// Infers the kinds of nodes each stage may select when the plan
+    /// runs on nodes of the kinds in `input`, and reports stages that
+    /// can never select anything or that have no effect.  Closures
+    /// are assumed to select anything.  Only the first unsatisfiable
+    /// stage of a chain is reported, since the stages after it never
+    /// run.
+    pub fn analyze(&self, input: Kinds) -> Analysis {
+        let mut analyzer = Analyzer { warnings: Vec::new() };
+        let (kinds, output) = analyzer.chain(&self.ops, State { kinds: input, synthetic: false });
+        Analysis { kinds, output: output.kinds, warnings: analyzer.warnings }
+    }
+
+    /// Optimize the plan
+    ///
+    /// Returns a plan selecting the same nodes, rewritten to run
+    /// faster:
+    ///
+    /// - `child().parent()` becomes `wherein(child())`, and likewise
+    ///   for `key` and `at`
+    /// - `union(x, x)` becomes `x` when `x` selects no node twice
+    /// - Consecutive type tests are merged, so `string().string()`
+    ///   becomes `string()` and `len_gt(1).len_between(0, 5)` becomes
+    ///   `len_between(2, 5)`
+    /// - `wherein(node())` is removed
+    /// - In a run of consecutive filters, cheap tests such as
+    ///   `string()` are moved ahead of expensive ones such as
+    ///   `wherein(descend().null())`
+    /// - Filters which don't depend on the current node, such as
+    ///   `wherein(root().key("debug"))`, are hoisted ahead of
+    ///   `descend()` and other navigation, so they run once rather
+    ///   than once for every node found
+    ///
+    /// Closures given to `filter`, `filter_path` and `flat_map` are
+    /// never moved past.  Nested plans are optimized too.
+    pub fn optimize(&self) -> Plan {
+        let mut ops = Vec::with_capacity(self.ops.len());
+        for op in self.ops.iter() {
+            simplify(&mut ops, optimize_op(op))
+        }
+        sort_filters(&mut ops);
+        hoist_filters(&mut ops);
+        let mut optimized = Vec::with_capacity(ops.len());
+        for op in ops {
+            simplify(&mut optimized, op)
+        }
+        Plan { ops: optimized }
+    }
+}
+
+/// Optimize the plans nested in stage `op`
+fn optimize_op(op: &Op) -> Op {
+    let list = |list: &[Plan]| list.iter().map(Plan::optimize).collect();
+    match *op {
+        Op::DescendUntil(ref filter) => Op::DescendUntil(filter.optimize()),
+        Op::DescendPrune(ref filter) => Op::DescendPrune(filter.optimize()),
+        Op::Wherein(ref filter) => Op::Wherein(filter.optimize()),
+        Op::Compare(ref left, op, ref right) => Op::Compare(optimize_expr(left), op, optimize_expr(right)),
+        Op::Union(ref left, ref right) => Op::Union(left.optimize(), right.optimize()),
+        Op::Intersect(ref left, ref right) => Op::Intersect(left.optimize(), right.optimize()),
+        Op::Diff(ref left, ref right) => Op::Diff(left.optimize(), right.optimize()),
+        Op::And(ref left, ref right) => Op::And(left.optimize(), right.optimize()),
+        Op::Or(ref left, ref right) => Op::Or(left.optimize(), right.optimize()),
+        Op::UnionAll(ref plans) => Op::UnionAll(list(plans)),
+        Op::AnyOf(ref plans) => Op::AnyOf(list(plans)),
+        Op::AllOf(ref plans) => Op::AllOf(list(plans)),
+        Op::IfThenElse(ref cond, ref then, ref otherwise) =>
+            Op::IfThenElse(cond.optimize(), then.optimize(), otherwise.optimize()),
+        Op::Coalesce(ref plans) => Op::Coalesce(list(plans)),
+        Op::UnionValues(ref left, ref right) => Op::UnionValues(left.optimize(), right.optimize()),
+        Op::IntersectValues(ref left, ref right) => Op::IntersectValues(left.optimize(), right.optimize()),
+        Op::DiffValues(ref left, ref right) => Op::DiffValues(left.optimize(), right.optimize()),
+        ref op => op.clone()
+    }
+}
+
+/// Optimize the plans nested in expression `expr`
+fn optimize_expr(expr: &ExprPlan) -> ExprPlan {
+    match *expr {
+        ExprPlan::Select(ref plan) => ExprPlan::Select(plan.optimize()),
+        ExprPlan::Lit(ref value) => ExprPlan::Lit(value.clone()),
+        ExprPlan::Map(ref plan, ref expr) => ExprPlan::Map(plan.optimize(), Box::new(optimize_expr(expr))),
+        ExprPlan::Arith(ref left, op, ref right) =>
+            ExprPlan::Arith(Box::new(optimize_expr(left)), op, Box::new(optimize_expr(right))),
+        ExprPlan::Concat(ref left, ref right) =>
+            ExprPlan::Concat(Box::new(optimize_expr(left)), Box::new(optimize_expr(right))),
+        ExprPlan::Apply(ref inner, func) => ExprPlan::Apply(Box::new(optimize_expr(inner)), func)
+    }
+}
+
+/// Return the kind of node stage `op` tests for, if it selects only
+/// nodes of that kind and checks the kind itself
+fn tested_kind(op: &Op) -> Option<Kind> {
+    match *op {
+        Op::Is(kind) => Some(kind),
+        Op::StringEquals(..) => Some(Kind::String),
+        Op::BooleanEquals(..) => Some(Kind::Boolean),
+        Op::U64Equals(..) => Some(Kind::U64),
+        Op::I64Equals(..) => Some(Kind::I64),
+        Op::F64Equals(..) => Some(Kind::F64),
+        _ => None
+    }
+}
+
+/// Append stage `op` to `ops`, simplifying it with the stage before
+fn simplify(ops: &mut Vec<Op>, op: Op) {
+    match (ops.last(), &op) {
+        // `union` skips repeated nodes but its input alone may not
+        (_, Op::Union(left, right))
+            if left == right && yields_distinct(&[&ops[..], &left.ops[..]].concat()) => {
+            for op in left.ops.iter() {
+                simplify(ops, op.clone())
+            }
+            return
+        },
+        // `parent` skips repeated nodes but `wherein` doesn't
+        (Some(&Op::Child), &Op::Parent) | (Some(&Op::Key(..)), &Op::Parent) | (Some(&Op::At(..)), &Op::Parent)
+            if yields_distinct(&ops[..ops.len() - 1]) => {
+            let last = ops.pop().unwrap();
+            return simplify(ops, Op::Wherein(Plan { ops: vec![last] }))
+        },
+        (_, Op::Wherein(filter)) if filter.ops.is_empty() => return,
+        (Some(last), &Op::Is(kind)) if tested_kind(last) == Some(kind) => return,
+        (Some(&Op::Is(kind)), _) if tested_kind(&op) == Some(kind) => {
+            ops.pop();
+        },
+        (Some(&Op::LenRange(min, max)), &Op::LenRange(other_min, other_max)) => {
+            ops.pop();
+            return ops.push(Op::LenRange(min.max(other_min), max.min(other_max)))
+        },
+        _ => ()
+    }
+    ops.push(op)
+}
+
+/// Check whether stages `ops` select each node at most once
+fn yields_distinct(ops: &[Op]) -> bool {
+    match ops.split_last() {
+        None => true,
+        Some((op, rest)) => match *op {
+            Op::Parent | Op::Root | Op::Descend | Op::DescendBreadth | Op::DescendMax(..) |
+            Op::DescendOrSelf | Op::DescendUntil(..) | Op::DescendPrune(..) |
+            Op::Union(..) | Op::UnionAll(..) | Op::Distinct => true,
+            // Distinct nodes have distinct children
+            Op::Child | Op::Key(..) | Op::At(..) | Op::Filter(..) | Op::FilterPath(..) => yields_distinct(rest),
+            _ => filter_cost(op).is_some() && yields_distinct(rest)
+        }
+    }
+}
+
+/// Return the relative cost of stage `op` if it is a filter which
+/// can be reordered with other filters
+///
+/// A filter selects its input node or nothing, depending only on the
+/// node and its path.
+fn filter_cost(op: &Op) -> Option<u8> {
+    match *op {
+        Op::Is(..) | Op::StringEquals(..) | Op::BooleanEquals(..) | Op::U64Equals(..) |
+        Op::I64Equals(..) | Op::F64Equals(..) | Op::F64Test(..) | Op::LenRange(..) |
+        Op::Position(..) | Op::AtDepth(..) | Op::Named(..) | Op::Leaf => Some(0),
+        Op::EqualsJson(..) | Op::ContainsJson(..) => Some(1),
+        Op::Wherein(..) | Op::Compare(..) => Some(2),
+        _ => None
+    }
+}
+
+/// Order each run of consecutive filters in `ops` from cheapest to
+/// most expensive
+fn sort_filters(ops: &mut [Op]) {
+    let mut start = 0;
+    while start < ops.len() {
+        let end = start + ops[start..].iter().take_while(|op| filter_cost(op).is_some()).count();
+        ops[start..end].sort_by_key(filter_cost);
+        start = end + 1;
+    }
+}
+
+/// Check whether stage `op` is a filter which doesn't depend on the
+/// current node, only on the root of its document
+fn is_root_filter(op: &Op) -> bool {
+    match *op {
+        Op::Wherein(ref filter) => filter.ops.first() == Some(&Op::Root),
+        _ => false
+    }
+}
+
+/// Check whether a filter depending only on the root of the document
+/// can be moved ahead of stage `op`
+///
+/// This holds for filters and navigation, which select nodes of the
+/// same document as their input: the filter selects all or none of
+/// their output exactly when it selects all or none of their input.
+fn can_hoist_past(op: &Op) -> bool {
+    match *op {
+        Op::At(..) | Op::Key(..) | Op::Child | Op::Parent | Op::Root | Op::Sibling(..) |
+        Op::Descend | Op::DescendBreadth | Op::DescendMax(..) | Op::DescendOrSelf |
+        Op::DescendUntil(..) | Op::DescendPrune(..) | Op::Ascend => true,
+        _ => filter_cost(op).is_some() && !is_root_filter(op)
+    }
+}
+
+/// Move filters which don't depend on the current node ahead of the
+/// navigation and filters before them
+fn hoist_filters(ops: &mut [Op]) {
+    for i in 0..ops.len() {
+        if is_root_filter(&ops[i]) {
+            let mut j = i;
+            while j > 0 && can_hoist_past(&ops[j - 1]) {
+                ops.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Plan {
+    /// Write the plan as the method chain of the selector it describes
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Stages(&self.ops).fmt(f)
+    }
+}
+
+/// Plan of an expression
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprPlan {
+    /// Values are the nodes selected by a selector
+    Select(Plan),
+    /// `lit(value)`
+    Lit(Json),
+    /// `map(expr)` on a selector
+    Map(Plan, Box<ExprPlan>),
+    /// `add(right)` and the other arithmetic operators
+    Arith(Box<ExprPlan>, ArithOp, Box<ExprPlan>),
+    /// `concat(right)`
+    Concat(Box<ExprPlan>, Box<ExprPlan>),
+    /// `length()` and the other functions
+    Apply(Box<ExprPlan>, Func)
+}
+
+impl From<Plan> for ExprPlan {
+    fn from(plan: Plan) -> ExprPlan {
+        ExprPlan::Select(plan)
+    }
+}
+
+impl fmt::Display for ExprPlan {
+    /// Write the expression as the method chain building it
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExprPlan::Select(ref plan) => plan.fmt(f),
+            ExprPlan::Lit(ref value) => Lit { value: value.clone() }.fmt(f),
+            ExprPlan::Map(ref plan, ref expr) => Map { inner: Stages(&plan.ops), expr: ExprStage(expr) }.fmt(f),
+            ExprPlan::Arith(ref left, op, ref right) =>
+                Arith { left: ExprStage(left), op, right: ExprStage(right) }.fmt(f),
+            ExprPlan::Concat(ref left, ref right) => Concat { left: ExprStage(left), right: ExprStage(right) }.fmt(f),
+            ExprPlan::Apply(ref inner, func) => Apply { inner: ExprStage(inner), func }.fmt(f)
+        }
+    }
+}
+
+/// Conversion of selectors and expressions into plans
+///
+/// Selectors convert to a `Plan`, expressions to an `ExprPlan`, and
+/// collections of selectors to a `Vec<Plan>`.  Selectors built with
+/// closures can only be converted if the closures are `Clone`, `Send`,
+/// `Sync` and `'static`, since the plan shares a copy of them.
+pub trait ToPlan {
+    /// Plan produced
+    type Output;
+
+    /// Perform conversion
+    fn to_plan(&self) -> Self::Output;
+}
+
+impl ToPlan for Plan {
+    type Output = Plan;
+
+    fn to_plan(&self) -> Plan {
+        self.clone()
+    }
+}
+
+impl ToPlan for ExprPlan {
+    type Output = ExprPlan;
+
+    fn to_plan(&self) -> ExprPlan {
+        self.clone()
+    }
+}
+
+impl ToPlan for Node {
+    type Output = Plan;
+
+    fn to_plan(&self) -> Plan {
+        Plan::new()
+    }
+}
+
+macro_rules! plan_stage {
+    ($([$($params:tt)*] $sel:ty => |$s:ident| $op:expr;)+) => {
+        $(
+            impl<$($params)*> ToPlan for $sel {
+                type Output = Plan;
+
+                fn to_plan(&self) -> Plan {
+                    let $s = self;
+                    $s.inner.to_plan().then($op)
+                }
+            }
+        )+
+    }
+}
+
+plan_stage! {
+    [S:ToPlan<Output=Plan>] ObjectSel<S> => |s| Op::Is(Kind::Object);
+    [S:ToPlan<Output=Plan>] ListSel<S> => |s| Op::Is(Kind::Array);
+    [S:ToPlan<Output=Plan>] StringSel<S> => |s| Op::Is(Kind::String);
+    ['a,S:ToPlan<Output=Plan>] StringEquals<'a,S> => |s| Op::StringEquals(s.comp.to_string());
+    [S:ToPlan<Output=Plan>] LenRange<S> => |s| Op::LenRange(s.min, s.max);
+    [S:ToPlan<Output=Plan>] BooleanSel<S> => |s| Op::Is(Kind::Boolean);
+    [S:ToPlan<Output=Plan>] BooleanEquals<S> => |s| Op::BooleanEquals(s.comp);
+    [S:ToPlan<Output=Plan>] U64Sel<S> => |s| Op::Is(Kind::U64);
+    [S:ToPlan<Output=Plan>] U64Equals<S> => |s| Op::U64Equals(s.comp);
+    [S:ToPlan<Output=Plan>] I64Sel<S> => |s| Op::Is(Kind::I64);
+    [S:ToPlan<Output=Plan>] I64Equals<S> => |s| Op::I64Equals(s.comp);
+    [S:ToPlan<Output=Plan>] F64Sel<S> => |s| Op::Is(Kind::F64);
+    [S:ToPlan<Output=Plan>] F64Equals<S> => |s| Op::F64Equals(s.comp);
+    [S:ToPlan<Output=Plan>] F64Pred<S> => |s| Op::F64Test(s.test);
+    [S:ToPlan<Output=Plan>] NullSel<S> => |s| Op::Is(Kind::Null);
+    [S:ToPlan<Output=Plan>] At<S> => |s| Op::At(s.index);
+    ['f,S:ToPlan<Output=Plan>] Key<'f,S> => |s| Op::Key(s.name.to_string());
+    [S:ToPlan<Output=Plan>] Child<S> => |s| Op::Child;
+    [S:ToPlan<Output=Plan>] Parent<S> => |s| Op::Parent;
+    [S:ToPlan<Output=Plan>] RootSel<S> => |s| Op::Root;
+    [S:ToPlan<Output=Plan>] Sibling<S> => |s| Op::Sibling(s.axis);
+    [S:ToPlan<Output=Plan>] PositionSel<S> => |s| Op::Position(s.position);
+    [S:ToPlan<Output=Plan>] AtDepth<S> => |s| Op::AtDepth(s.depth);
+    ['f,S:ToPlan<Output=Plan>] Named<'f,S> => |s| Op::Named(s.pattern.to_string());
+    [S:ToPlan<Output=Plan>] Leaf<S> => |s| Op::Leaf;
+    [S:ToPlan<Output=Plan>] Descend<S> => |s| Op::Descend;
+    [S:ToPlan<Output=Plan>] DescendBreadth<S> => |s| Op::DescendBreadth;
+    [S:ToPlan<Output=Plan>] DescendMax<S> => |s| Op::DescendMax(s.depth);
+    [S:ToPlan<Output=Plan>] DescendOrSelf<S> => |s| Op::DescendOrSelf;
+    [S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] DescendUntil<S,T> => |s| Op::DescendUntil(s.filter.to_plan());
+    [S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] DescendPrune<S,T> => |s| Op::DescendPrune(s.filter.to_plan());
+    [S:ToPlan<Output=Plan>] Ascend<S> => |s| Op::Ascend;
+    [S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] Wherein<S,T> => |s| Op::Wherein(s.filter.to_plan());
+    ['j,S:ToPlan<Output=Plan>] EqualsJson<'j,S> => |s| Op::EqualsJson(s.value.clone().into_owned());
+    ['j,S:ToPlan<Output=Plan>] ContainsJson<'j,S> => |s| Op::ContainsJson(s.template.clone().into_owned());
+    [S:ToPlan<Output=Plan>,P:Fn(&Json) -> bool + Clone + Send + Sync + 'static] Filter<S,P> =>
+        |s| Op::Filter(Closure(Arc::new(s.pred.clone())));
+    [S:ToPlan<Output=Plan>,P:for<'a,'b> Fn(&JsonPath<'a,'b>) -> bool + Clone + Send + Sync + 'static] FilterPath<S,P> =>
+        |s| Op::FilterPath(Closure(Arc::new(s.pred.clone())));
+    [S:ToPlan<Output=Plan>,M:for<'j> Fn(&'j Json) -> Vec<&'j Json> + Clone + Send + Sync + 'static] FlatMap<S,M> =>
+        |s| Op::FlatMap(Closure(Arc::new(s.f.clone())));
+    [I:ToPlan<Output=Plan>,S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] Union<I,S,T> =>
+        |s| Op::Union(s.left.to_plan(), s.right.to_plan());
+    [I:ToPlan<Output=Plan>,S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] Intersect<I,S,T> =>
+        |s| Op::Intersect(s.left.to_plan(), s.right.to_plan());
+    [I:ToPlan<Output=Plan>,S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] Diff<I,S,T> =>
+        |s| Op::Diff(s.left.to_plan(), s.right.to_plan());
+    [I:ToPlan<Output=Plan>,S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] AndSel<I,S,T> =>
+        |s| Op::And(s.left.to_plan(), s.right.to_plan());
+    [I:ToPlan<Output=Plan>,S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] OrSel<I,S,T> =>
+        |s| Op::Or(s.left.to_plan(), s.right.to_plan());
+    [I:ToPlan<Output=Plan>,L:ToPlan<Output=Vec<Plan>>] UnionAll<I,L> => |s| Op::UnionAll(s.list.to_plan());
+    [I:ToPlan<Output=Plan>,L:ToPlan<Output=Vec<Plan>>] AnyOf<I,L> => |s| Op::AnyOf(s.list.to_plan());
+    [I:ToPlan<Output=Plan>,L:ToPlan<Output=Vec<Plan>>] AllOf<I,L> => |s| Op::AllOf(s.list.to_plan());
+    [I:ToPlan<Output=Plan>,C:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>,E:ToPlan<Output=Plan>] IfThenElse<I,C,T,E> =>
+        |s| Op::IfThenElse(s.cond.to_plan(), s.then.to_plan(), s.otherwise.to_plan());
+    [I:ToPlan<Output=Plan>,L:ToPlan<Output=Vec<Plan>>] Coalesce<I,L> => |s| Op::Coalesce(s.list.to_plan());
+    [S:ToPlan<Output=Plan>] Distinct<S> => |s| Op::Distinct;
+    [I:ToPlan<Output=Plan>,S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] UnionValues<I,S,T> =>
+        |s| Op::UnionValues(s.left.to_plan(), s.right.to_plan());
+    [I:ToPlan<Output=Plan>,S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] IntersectValues<I,S,T> =>
+        |s| Op::IntersectValues(s.left.to_plan(), s.right.to_plan());
+    [I:ToPlan<Output=Plan>,S:ToPlan<Output=Plan>,T:ToPlan<Output=Plan>] DiffValues<I,S,T> =>
+        |s| Op::DiffValues(s.left.to_plan(), s.right.to_plan());
+}
+
+impl<I,S,T> ToPlan for Compare<I,S,T>
+    where I: ToPlan<Output=Plan>, S: ToPlan, T: ToPlan, S::Output: Into<ExprPlan>, T::Output: Into<ExprPlan> {
+    type Output = Plan;
+
+    fn to_plan(&self) -> Plan {
+        self.inner.to_plan().then(Op::Compare(self.left.to_plan().into(), self.op, self.right.to_plan().into()))
+    }
+}
+
+impl ToPlan for Lit {
+    type Output = ExprPlan;
+
+    fn to_plan(&self) -> ExprPlan {
+        ExprPlan::Lit(self.value.clone())
+    }
+}
+
+impl<S,E> ToPlan for Map<S,E> where S: ToPlan<Output=Plan>, E: ToPlan, E::Output: Into<ExprPlan> {
+    type Output = ExprPlan;
+
+    fn to_plan(&self) -> ExprPlan {
+        ExprPlan::Map(self.inner.to_plan(), Box::new(self.expr.to_plan().into()))
+    }
+}
+
+impl<L,R> ToPlan for Arith<L,R> where L: ToPlan, R: ToPlan, L::Output: Into<ExprPlan>, R::Output: Into<ExprPlan> {
+    type Output = ExprPlan;
+
+    fn to_plan(&self) -> ExprPlan {
+        ExprPlan::Arith(Box::new(self.left.to_plan().into()), self.op, Box::new(self.right.to_plan().into()))
+    }
+}
+
+impl<L,R> ToPlan for Concat<L,R> where L: ToPlan, R: ToPlan, L::Output: Into<ExprPlan>, R::Output: Into<ExprPlan> {
+    type Output = ExprPlan;
+
+    fn to_plan(&self) -> ExprPlan {
+        ExprPlan::Concat(Box::new(self.left.to_plan().into()), Box::new(self.right.to_plan().into()))
+    }
+}
+
+impl<E> ToPlan for Apply<E> where E: ToPlan, E::Output: Into<ExprPlan> {
+    type Output = ExprPlan;
+
+    fn to_plan(&self) -> ExprPlan {
+        ExprPlan::Apply(Box::new(self.inner.to_plan().into()), self.func)
+    }
+}
+
+impl<S:ToPlan<Output=Plan>> ToPlan for [S] {
+    type Output = Vec<Plan>;
+
+    fn to_plan(&self) -> Vec<Plan> {
+        self.iter().map(ToPlan::to_plan).collect()
+    }
+}
+
+impl<S:ToPlan<Output=Plan>,const N: usize> ToPlan for [S; N] {
+    type Output = Vec<Plan>;
+
+    fn to_plan(&self) -> Vec<Plan> {
+        self.iter().map(ToPlan::to_plan).collect()
+    }
+}
+
+impl<S:ToPlan<Output=Plan>> ToPlan for Vec<S> {
+    type Output = Vec<Plan>;
+
+    fn to_plan(&self) -> Vec<Plan> {
+        self.iter().map(ToPlan::to_plan).collect()
+    }
+}
+
+impl<L:ToPlan+?Sized> ToPlan for &L {
+    type Output = L::Output;
+
+    fn to_plan(&self) -> L::Output {
+        (**self).to_plan()
+    }
+}
+
+macro_rules! tuple_lower {
+    ($($index:tt: $name:ident),+) => {
+        impl<$($name:ToPlan<Output=Plan>),+> ToPlan for ($($name,)+) {
+            type Output = Vec<Plan>;
+
+            fn to_plan(&self) -> Vec<Plan> {
+                vec![$(self.$index.to_plan()),+]
+            }
+        }
+    }
+}
+
+tuple_lower!(0: A);
+tuple_lower!(0: A, 1: B);
+tuple_lower!(0: A, 1: B, 2: C);
+tuple_lower!(0: A, 1: B, 2: C, 3: D);
+tuple_lower!(0: A, 1: B, 2: C, 3: D, 4: E);
+tuple_lower!(0: A, 1: B, 2: C, 3: D, 4: E, 5: G);
+tuple_lower!(0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H);
+tuple_lower!(0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I);
+tuple_lower!(0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I, 8: J);
+tuple_lower!(0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I, 8: J, 9: K);
+tuple_lower!(0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I, 8: J, 9: K, 10: L);
+tuple_lower!(0: A, 1: B, 2: C, 3: D, 4: E, 5: G, 6: H, 7: I, 8: J, 9: K, 10: L, 11: M);
+
+/// Stages of a plan run as a selector
+///
+/// Each stage runs and writes the selector it describes, built on the
+/// fly on top of the stages before it by `with_stage`.  Closures
+/// passed between stages are boxed as trait objects, since the
+/// stages aren't known to the compiler.
+#[derive(Clone, Copy)]
+struct Stages<'p>(&'p [Op]);
+
+/// Closure receiving the nodes selected by a stage
+type Sink<'s,'a> = dyn for<'c> FnMut(&JsonPath<'a,'c>) + 's;
+
+impl<'p> fmt::Display for Stages<'p> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.split_last() {
+            Some((op, rest)) => with_stage(op, Stages(rest), Write(f)),
+            None => Node { _dummy: () }.fmt(f)
+        }
+    }
+}
+
+impl<'p> Selector for Stages<'p> {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        self.run(input, &mut f)
+    }
+
+    fn fmt_inner(&self, f: &mut fmt::Formatter, shorthand: bool) -> fmt::Result {
+        if self.0.is_empty() {
+            Node { _dummy: () }.fmt_inner(f, shorthand)
+        } else {
+            write!(f, "{}.", self)
+        }
+    }
+}
+
+impl<'p> Stages<'p> {
+    fn run<'a,'b>(&self, input: &JsonPath<'a,'b>, f: &mut Sink<'_,'a>) {
+        let (op, inner) = match self.0.split_last() {
+            Some((op, rest)) => (op, Stages(rest)),
+            None => {
+                identify_stage(self.0.as_ptr() as usize);
+                return Node { _dummy: () }.select(input, f)
+            }
+        };
+        identify_stage(op as *const Op as usize);
+        with_stage(op, inner, Run { input, f })
+    }
+}
+
+/// Action on the selector described by a stage
+trait StageFn {
+    /// Result of the action
+    type Output;
+
+    /// Perform the action on selector `sel`
+    fn call<S:Selector+fmt::Display>(self, sel: S) -> Self::Output;
+}
+
+/// Build the selector described by stage `op` on top of `inner`
+/// and perform action `g` on it
+fn with_stage<I:Selector+fmt::Display,G:StageFn>(op: &Op, inner: I, g: G) -> G::Output {
+    match *op {
+        Op::Is(Kind::Object) => g.call(ObjectSel { inner }),
+        Op::Is(Kind::Array) => g.call(ListSel { inner }),
+        Op::Is(Kind::String) => g.call(StringSel { inner }),
+        Op::Is(Kind::Boolean) => g.call(BooleanSel { inner }),
+        Op::Is(Kind::U64) => g.call(U64Sel { inner }),
+        Op::Is(Kind::I64) => g.call(I64Sel { inner }),
+        Op::Is(Kind::F64) => g.call(F64Sel { inner }),
+        Op::Is(Kind::Null) => g.call(NullSel { inner }),
+        Op::StringEquals(ref comp) =>
+            g.call(StringEquals { inner, comp: Cow::Borrowed(&comp[..]) }),
+        Op::BooleanEquals(comp) => g.call(BooleanEquals { inner, comp }),
+        Op::U64Equals(comp) => g.call(U64Equals { inner, comp }),
+        Op::I64Equals(comp) => g.call(I64Equals { inner, comp }),
+        Op::F64Equals(comp) => g.call(F64Equals { inner, comp }),
+        Op::F64Test(test) => g.call(F64Pred { inner, test }),
+        Op::LenRange(min, max) => g.call(LenRange { inner, min, max }),
+        Op::At(index) => g.call(At { inner, index }),
+        Op::Key(ref name) => g.call(Key { inner, name: Cow::Borrowed(&name[..]) }),
+        Op::Child => g.call(Child { inner }),
+        Op::Parent => g.call(Parent { inner }),
+        Op::Root => g.call(RootSel { inner }),
+        Op::Sibling(axis) => g.call(Sibling { inner, axis }),
+        Op::Position(position) => g.call(PositionSel { inner, position }),
+        Op::AtDepth(depth) => g.call(AtDepth { inner, depth }),
+        Op::Named(ref pattern) =>
+            g.call(Named { inner, pattern: Cow::Borrowed(&pattern[..]) }),
+        Op::Leaf => g.call(Leaf { inner }),
+        Op::Descend => g.call(Descend { inner }),
+        Op::DescendBreadth => g.call(DescendBreadth { inner }),
+        Op::DescendMax(depth) => g.call(DescendMax { inner, depth }),
+        Op::DescendOrSelf => g.call(DescendOrSelf { inner }),
+        Op::DescendUntil(ref filter) =>
+            g.call(DescendUntil { inner, filter: Stages(&filter.ops) }),
+        Op::DescendPrune(ref filter) =>
+            g.call(DescendPrune { inner, filter: Stages(&filter.ops) }),
+        Op::Ascend => g.call(Ascend { inner }),
+        Op::Wherein(ref filter) => g.call(Wherein { inner, filter: Stages(&filter.ops) }),
+        Op::EqualsJson(ref value) => g.call(EqualsJson { inner, value: Cow::Borrowed(value) }),
+        Op::ContainsJson(ref template) =>
+            g.call(ContainsJson { inner, template: Cow::Borrowed(template) }),
+        Op::Compare(ref left, op, ref right) =>
+            g.call(Compare { inner, left: ExprStage(left), op, right: ExprStage(right) }),
+        Op::Filter(ref pred) => g.call(Filter { inner, pred: &**pred }),
+        Op::FilterPath(ref pred) => g.call(FilterPath { inner, pred: &**pred }),
+        Op::FlatMap(ref map) => g.call(FlatMap { inner, f: &**map }),
+        Op::Union(ref left, ref right) =>
+            g.call(Union { inner, left: Stages(&left.ops), right: Stages(&right.ops) }),
+        Op::Intersect(ref left, ref right) =>
+            g.call(Intersect { inner, left: Stages(&left.ops), right: Stages(&right.ops) }),
+        Op::Diff(ref left, ref right) =>
+            g.call(Diff { inner, left: Stages(&left.ops), right: Stages(&right.ops) }),
+        Op::And(ref left, ref right) =>
+            g.call(AndSel { inner, left: Stages(&left.ops), right: Stages(&right.ops) }),
+        Op::Or(ref left, ref right) =>
+            g.call(OrSel { inner, left: Stages(&left.ops), right: Stages(&right.ops) }),
+        Op::UnionAll(ref list) => g.call(UnionAll { inner, list: &list[..] }),
+        Op::AnyOf(ref list) => g.call(AnyOf { inner, list: &list[..] }),
+        Op::AllOf(ref list) => g.call(AllOf { inner, list: &list[..] }),
+        Op::IfThenElse(ref cond, ref then, ref otherwise) =>
+            g.call(IfThenElse {
+                inner, cond: Stages(&cond.ops), then: Stages(&then.ops), otherwise: Stages(&otherwise.ops)
+            }),
+        Op::Coalesce(ref list) => g.call(Coalesce { inner, list: &list[..] }),
+        Op::Distinct => g.call(Distinct { inner }),
+        Op::UnionValues(ref left, ref right) =>
+            g.call(UnionValues { inner, left: Stages(&left.ops), right: Stages(&right.ops) }),
+        Op::IntersectValues(ref left, ref right) =>
+            g.call(IntersectValues { inner, left: Stages(&left.ops), right: Stages(&right.ops) }),
+        Op::DiffValues(ref left, ref right) =>
+            g.call(DiffValues { inner, left: Stages(&left.ops), right: Stages(&right.ops) })
+    }
+}
+
+/// Action running a selector
+struct Run<'r,'a:'r,'b:'r,'s:'r> {
+    input: &'r JsonPath<'a,'b>,
+    f: &'r mut Sink<'s,'a>
+}
+
+impl<'r,'a,'b,'s> StageFn for Run<'r,'a,'b,'s> {
+    type Output = ();
+
+    fn call<S:Selector+fmt::Display>(self, sel: S) {
+        sel.select(self.input, self.f)
+    }
+}
+
+/// Action writing a selector
+struct Write<'r,'f:'r>(&'r mut fmt::Formatter<'f>);
+
+impl<'r,'f> StageFn for Write<'r,'f> {
+    type Output = fmt::Result;
+
+    fn call<S:Selector+fmt::Display>(self, sel: S) -> fmt::Result {
+        sel.fmt(self.0)
+    }
+}
+
+/// Selector on which the method call of a single stage is written
+///
+/// It writes nothing itself, not even `node()` before a method
+/// without a shorthand function.
+#[derive(Clone, Copy)]
+struct Bare;
+
+impl fmt::Display for Bare {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        Ok(())
+    }
+}
+
+impl Selector for Bare {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        f(input)
+    }
+
+    fn fmt_inner(&self, _: &mut fmt::Formatter, _: bool) -> fmt::Result {
+        Ok(())
+    }
+}
+
+impl Selector for Plan {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        Stages(&self.ops).select(input, f)
+    }
+
+    fn fmt_inner(&self, f: &mut fmt::Formatter, shorthand: bool) -> fmt::Result {
+        Stages(&self.ops).fmt_inner(f, shorthand)
+    }
+}
+
+/// Expression plan run as an expression
+struct ExprStage<'p>(&'p ExprPlan);
+
+impl<'p> fmt::Display for ExprStage<'p> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<'p> Expr for ExprStage<'p> {
+    fn eval<'a,'b,F>(&self, input: &JsonPath<'a,'b>, mut f: F)
+                     where F: FnMut(Cow<'a,Json>) {
+        self.run(input, &mut f)
+    }
+}
+
+impl<'p> ExprStage<'p> {
+    fn run<'a,'b>(&self, input: &JsonPath<'a,'b>, f: &mut dyn FnMut(Cow<'a,Json>)) {
+        match *self.0 {
+            ExprPlan::Select(ref plan) => plan.eval(input, f),
+            ExprPlan::Lit(ref value) => f(Cow::Owned(value.clone())),
+            ExprPlan::Map(ref plan, ref expr) =>
+                Map { inner: Stages(&plan.ops), expr: ExprStage(expr) }.eval(input, f),
+            ExprPlan::Arith(ref left, op, ref right) =>
+                Arith { left: ExprStage(left), op, right: ExprStage(right) }.eval(input, f),
+            ExprPlan::Concat(ref left, ref right) =>
+                Concat { left: ExprStage(left), right: ExprStage(right) }.eval(input, f),
+            ExprPlan::Apply(ref inner, func) => Apply { inner: ExprStage(inner), func }.eval(input, f)
+        }
+    }
+}
+
+/// Problem found by `Plan::analyze`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    /// The last stage of `chain` never selects anything when its
+    /// input is of the kinds in `input`
+    Unsatisfiable {
+        /// Chain of stages up to the problem
+        chain: String,
+        /// Kinds of nodes given to the last stage
+        input: Kinds
+    },
+    /// The last stage of `chain` selects every node of its input
+    /// when it is of the kinds in `input`, so it has no effect
+    Redundant {
+        /// Chain of stages up to the problem
+        chain: String,
+        /// Kinds of nodes given to the last stage
+        input: Kinds
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::Unsatisfiable { ref chain, input } =>
+                write!(f, "{}: last stage never selects anything given {}", chain, input),
+            Warning::Redundant { ref chain, input } =>
+                write!(f, "{}: last stage has no effect given {}", chain, input)
+        }
+    }
+}
+
+/// Result of `Plan::analyze`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Analysis {
+    kinds: Vec<Kinds>,
+    output: Kinds,
+    warnings: Vec<Warning>
+}
+
+impl Analysis {
+    /// Return the kinds of nodes each stage of the plan may select
+    pub fn kinds(&self) -> &[Kinds] {
+        &self.kinds
+    }
+
+    /// Return the kinds of nodes the plan may select
+    pub fn output(&self) -> Kinds {
+        self.output
+    }
+
+    /// Return the problems found
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+}
+
+/// Nodes a stage may select
+#[derive(Clone, Copy)]
+struct State {
+    kinds: Kinds,
+    /// Whether the nodes may have been made up by a selector such as
+    /// `and`, whose parent is not a container
+    synthetic: bool
+}
+
+impl State {
+    fn nodes(kinds: Kinds) -> State {
+        State { kinds, synthetic: false }
+    }
+
+    fn join(self, other: State) -> State {
+        State { kinds: self.kinds | other.kinds, synthetic: self.synthetic || other.synthetic }
+    }
+
+    fn meet(self, kinds: Kinds) -> State {
+        State { kinds: self.kinds & kinds, synthetic: self.synthetic }
+    }
+}
+
+/// Return the kinds of nodes that may equal a node of the kinds in
+/// `kinds`, which compare numbers by value
+fn comparable(kinds: Kinds) -> Kinds {
+    if (kinds & Kinds::NUMBER).is_empty() {
+        kinds
+    } else {
+        kinds | Kinds::NUMBER
+    }
+}
+
+struct Analyzer {
+    warnings: Vec<Warning>
+}
+
+impl Analyzer {
+    /// Analyze the chain of stages `ops` given nodes `input`,
+    /// returning the kinds after each stage and the output
+    fn chain(&mut self, ops: &[Op], input: State) -> (Vec<Kinds>, State) {
+        let mut state = input;
+        let mut kinds = Vec::with_capacity(ops.len());
+        for (i, op) in ops.iter().enumerate() {
+            if !state.kinds.is_empty() {
+                let warned = self.warnings.len();
+                let next = self.stage(op, state);
+                if next.kinds.is_empty() && self.warnings.len() == warned {
+                    self.warnings.push(Warning::Unsatisfiable {
+                        chain: Stages(&ops[..i + 1]).to_string(),
+                        input: state.kinds
+                    })
+                } else if !next.kinds.is_empty() && Analyzer::redundant(op, state.kinds) {
+                    self.warnings.push(Warning::Redundant {
+                        chain: Stages(&ops[..i + 1]).to_string(),
+                        input: state.kinds
+                    })
+                }
+                state = next;
+            }
+            kinds.push(state.kinds);
+        }
+        (kinds, state)
+    }
+
+    /// Analyze plan `plan` given nodes `input`, returning its output
+    fn plan(&mut self, plan: &Plan, input: State) -> State {
+        self.chain(&plan.ops, input).1
+    }
+
+    /// Analyze the plans in `list`, returning the join of their
+    /// outputs and the number which may select anything
+    fn plans(&mut self, list: &[Plan], input: State) -> (State, usize) {
+        let mut output = State::nodes(Kinds::NONE);
+        let mut count = 0;
+        for plan in list {
+            let state = self.plan(plan, input);
+            if !state.kinds.is_empty() {
+                count += 1;
+            }
+            output = output.join(state);
+        }
+        (output, count)
+    }
+
+    fn expr(&mut self, expr: &ExprPlan, input: State) {
+        match *expr {
+            ExprPlan::Select(ref plan) => { self.plan(plan, input); },
+            ExprPlan::Lit(..) => (),
+            ExprPlan::Map(ref plan, ref expr) => {
+                let state = self.plan(plan, input);
+                if !state.kinds.is_empty() {
+                    self.expr(expr, state)
+                }
+            },
+            ExprPlan::Arith(ref left, _, ref right) | ExprPlan::Concat(ref left, ref right) => {
+                self.expr(left, input);
+                self.expr(right, input)
+            },
+            ExprPlan::Apply(ref inner, _) => self.expr(inner, input)
+        }
+    }
+
+    /// Check whether stage `op` selects every node of the kinds in
+    /// `input`
+    fn redundant(op: &Op, input: Kinds) -> bool {
+        match *op {
+            Op::Is(kind) => input.is_subset(kind.into()),
+            Op::LenRange(0, usize::MAX) => input.is_subset(Kinds::CONTAINER | Kind::String.into()),
+            Op::Wherein(ref filter) => filter.ops.is_empty(),
+            _ => false
+        }
+    }
+
+    /// Analyze stage `op` given nodes `input`, returning its output
+    fn stage(&mut self, op: &Op, input: State) -> State {
+        // Output of stages selecting children of their input
+        let children = |needed: Kinds| {
+            State::nodes(if (input.kinds & needed).is_empty() { Kinds::NONE } else { Kinds::ALL })
+        };
+        match *op {
+            Op::Is(kind) => input.meet(kind.into()),
+            Op::StringEquals(..) => input.meet(Kind::String.into()),
+            Op::BooleanEquals(..) => input.meet(Kind::Boolean.into()),
+            Op::U64Equals(..) => input.meet(Kind::U64.into()),
+            Op::I64Equals(..) => input.meet(Kind::I64.into()),
+            Op::F64Equals(..) | Op::F64Test(..) => input.meet(Kind::F64.into()),
+            Op::LenRange(min, max) if min > max => input.meet(Kinds::NONE),
+            Op::LenRange(..) => input.meet(Kinds::CONTAINER | Kind::String.into()),
+            Op::At(..) => children(Kind::Array.into()),
+            Op::Key(..) => children(Kind::Object.into()),
+            Op::Child | Op::Descend | Op::DescendBreadth | Op::DescendMax(..) => children(Kinds::CONTAINER),
+            Op::DescendUntil(ref filter) | Op::DescendPrune(ref filter) => {
+                self.plan(filter, State::nodes(Kinds::ALL));
+                children(Kinds::CONTAINER)
+            },
+            Op::DescendOrSelf => State { kinds: Kinds::ALL, synthetic: input.synthetic },
+            Op::Parent | Op::Ascend if input.synthetic => State::nodes(Kinds::ALL),
+            Op::Parent | Op::Ascend => State::nodes(Kinds::CONTAINER),
+            Op::Root => State::nodes(Kinds::ALL),
+            Op::Sibling(..) => State::nodes(Kinds::ALL),
+            Op::Position(..) | Op::AtDepth(..) | Op::Named(..) | Op::Leaf | Op::Distinct => input,
+            Op::Filter(..) | Op::FilterPath(..) => input,
+            Op::FlatMap(..) => State { kinds: Kinds::ALL, synthetic: true },
+            Op::Wherein(ref filter) => {
+                if self.plan(filter, input).kinds.is_empty() {
+                    input.meet(Kinds::NONE)
+                } else {
+                    input
+                }
+            },
+            Op::EqualsJson(ref value) => input.meet(comparable(Kind::of(value).into())),
+            Op::ContainsJson(ref template) => input.meet(comparable(Kind::of(template).into())),
+            Op::Compare(ref left, _, ref right) => {
+                self.expr(left, input);
+                self.expr(right, input);
+                input
+            },
+            Op::Union(ref left, ref right) | Op::UnionValues(ref left, ref right) => {
+                let left = self.plan(left, input);
+                left.join(self.plan(right, input))
+            },
+            Op::Intersect(ref left, ref right) => {
+                let left = self.plan(left, input);
+                left.meet(self.plan(right, input).kinds)
+            },
+            Op::IntersectValues(ref left, ref right) => {
+                let left = self.plan(left, input);
+                let right = self.plan(right, input);
+                left.meet(comparable(right.kinds)).join(right.meet(comparable(left.kinds)))
+            },
+            Op::Diff(ref left, ref right) | Op::DiffValues(ref left, ref right) => {
+                let left = self.plan(left, input);
+                self.plan(right, input);
+                left
+            },
+            Op::And(ref left, ref right) => {
+                let left = self.plan(left, input);
+                let right = self.plan(right, input);
+                boolean(!left.kinds.is_empty() && !right.kinds.is_empty())
+            },
+            Op::Or(ref left, ref right) => {
+                let left = self.plan(left, input);
+                let right = self.plan(right, input);
+                boolean(!left.kinds.is_empty() || !right.kinds.is_empty())
+            },
+            Op::AnyOf(ref list) => {
+                let (_, count) = self.plans(list, input);
+                boolean(count > 0)
+            },
+            Op::AllOf(ref list) => {
+                let (_, count) = self.plans(list, input);
+                boolean(count == list.len())
+            },
+            Op::UnionAll(ref list) | Op::Coalesce(ref list) => self.plans(list, input).0,
+            Op::IfThenElse(ref cond, ref then, ref otherwise) => {
+                self.plan(cond, input);
+                let then = self.plan(then, input);
+                then.join(self.plan(otherwise, input))
+            }
+        }
+    }
+}
+
+/// Output of stages such as `and` which select a made-up
+/// `Json::Boolean` node if `found`
+fn boolean(found: bool) -> State {
+    State { kinds: if found { Kind::Boolean.into() } else { Kinds::NONE }, synthetic: true }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Kind,Kinds,Op,Plan,ToPlan,Warning};
+    use super::super::{at,child,descend,key,list,null,object,root,string,uint64,union,Selector,Expr,Cmp,JsonExt};
+    use super::super::{flat_map,node,parent};
+    use serialize::json::Json;
+
+    #[test]
+    fn lower_display() {
+        let sel = list().child().wherein(
+            key("foo").string().len_gt(2).or(uint64().equals(42), descend().null()))
+            .union_all([at(0), at(1)])
+            .compare(key("n").add(key("m")), Cmp::Lt, key("max").length());
+        let plan = sel.to_plan();
+        assert_eq!(plan.to_string(), sel.to_string());
+        assert_eq!(plan.ops()[0], Op::Is(Kind::Array));
+        assert_eq!(plan.ops()[1], Op::Child);
+        assert_eq!(Plan::new().to_string(), "node()");
+        assert_eq!(Plan::new().child().to_string(), "child()");
+        assert_eq!(Plan::new().distinct().to_string(), "node().distinct()");
+        assert_eq!(child().to_plan().child().to_string(), "child().child()");
+        assert_eq!(Plan::from_ops(vec![Op::Distinct]).to_string(), "node().distinct()");
+    }
+
+    #[test]
+    fn analyze() {
+        let analysis = list().child().string().to_plan().analyze(Kinds::ALL);
+        assert_eq!(analysis.kinds(), &[Kind::Array.into(), Kinds::ALL, Kind::String.into()]);
+        assert_eq!(analysis.output(), Kind::String.into());
+        assert!(analysis.warnings().is_empty());
+
+        for sel in &[string().uint64().to_plan(), null().child().to_plan(), object().at(3).key("x").to_plan()] {
+            let analysis = sel.analyze(Kinds::ALL);
+            assert_eq!(analysis.output(), Kinds::NONE);
+            assert_eq!(analysis.warnings().len(), 1);
+            match analysis.warnings()[0] {
+                Warning::Unsatisfiable { .. } => (),
+                ref w => panic!("unexpected warning: {}", w)
+            }
+        }
+        assert_eq!(string().uint64().to_plan().analyze(Kinds::ALL).warnings()[0].to_string(),
+                   "string().uint64(): last stage never selects anything given string");
+
+        let analysis = object().wherein(key("a").string().string()).to_plan().analyze(Kinds::ALL);
+        assert_eq!(analysis.output(), Kind::Object.into());
+        assert_eq!(analysis.warnings(), &[Warning::Redundant {
+            chain: "key(\"a\").string().string()".to_string(),
+            input: Kind::String.into()
+        }]);
+
+        let analysis = object().wherein(key("a")).string().to_plan().analyze(Kinds::ALL);
+        assert_eq!(analysis.warnings().len(), 1);
+        assert_eq!(analysis.warnings()[0].to_string(),
+                   "object().wherein(key(\"a\")).string(): last stage never selects anything given object");
+        assert_eq!(format!("{}", Kinds::CONTAINER | Kind::Null.into()), "object, list or null");
+    }
+
+    #[test]
+    fn optimize() {
+        let cases = vec![
+            (child().parent().to_plan(), "wherein(child())"),
+            (key("a").parent().key("b").to_plan(), "wherein(key(\"a\")).key(\"b\")"),
+            (union(key("a"), key("a")).to_plan(), "key(\"a\")"),
+            (child().intersect(root(), root()).to_plan(), "child().intersect(root(), root())"),
+            (flat_map(|j| vec![j, j]).union(node(), node()).to_plan(), "flat_map(..).union(node(), node())"),
+            (string().wherein(key("x")).string().equals("a").to_plan(), "string().equals(\"a\").wherein(key(\"x\"))"),
+            (list().len_gt(1).to_plan().then(Op::LenRange(0, 5)), "list().len_between(2, 5)"),
+            (descend().string().wherein(root().key("debug")).to_plan(),
+             "wherein(root().key(\"debug\")).descend().string()"),
+            (child().filter(|j| j.is_object()).wherein(root()).to_plan(), "child().filter(..).wherein(root())"),
+            (flat_map(|j| vec![j, j]).key("a").parent().to_plan(), "flat_map(..).key(\"a\").parent()"),
+            (flat_map(|j| vec![j, j]).child().child().parent().to_plan(), "flat_map(..).child().child().parent()"),
+            (flat_map(|j| vec![j, j]).distinct().key("a").parent().to_plan(),
+             "flat_map(..).distinct().wherein(key(\"a\"))")
+        ];
+        for (plan, optimized) in cases {
+            assert_eq!(plan.optimize().to_string(), optimized);
+        }
+    }
+
+    #[test]
+    fn run_plan() {
+        let json: Json = r#"{"debug": true, "a": [{"b": "x"}, {"b": 2, "c": null}], "d": {"b": "y"}}"#.parse().unwrap();
+        let sels = vec![
+            descend().key("b").parent().to_plan(),
+            descend().wherein(key("b").string()).wherein(root().key("debug")).to_plan(),
+            union(key("a").child(), key("d")).to_plan(),
+            key("a").child().compare(key("b"), Cmp::Eq, key("b").add(uint64().equals(0))).to_plan(),
+            flat_map(|j| vec![j, j]).key("a").parent().to_plan(),
+            key("a").child().coalesce((parent(),)).child().parent().to_plan(),
+            child().intersect(root(), root()).to_plan(),
+            flat_map(|j| vec![j, j]).union(node(), node()).to_plan()
+        ];
+        for plan in sels {
+            assert_eq!(json.query(plan.clone()), json.query(plan.optimize()));
+        }
+
+        let sel = union(key("a").child().key("b"), key("d").key("b"));
+        assert_eq!(json.query(sel.to_plan()), json.query(sel.clone()));
+        assert_eq!(json.trace(sel.to_plan()).1.to_string(), json.trace(sel).1.to_string());
+
+        let json: Json = "[1, 2, 3]".parse().unwrap();
+        let sel = child().intersect(root(), root());
+        assert_eq!(json.query(sel.clone()).len(), 5);
+        assert_eq!(json.query(sel.to_plan().optimize()).len(), 5);
+    }
+}
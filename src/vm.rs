@@ -0,0 +1,883 @@
+//! Compiled programs of selectors
+//!
+//! A `Plan` runs by building the selector each of its stages
+//! describes, one stage at a time.  `Program::compile` instead
+//! translates a plan into a flat sequence of instructions, which a
+//! small interpreter runs without building anything.  Programs don't
+//! depend on the types of the selectors they came from, so they keep
+//! compile times and binary size down in crates with many queries.
+//!
+//! That is a trade-off rather than a speed-up.  A selector is a
+//! chain of generic structs which the compiler inlines into one
+//! loop, while a program dispatches on every instruction for every
+//! node and passes nodes on through closures it can't inline.
+//! Programs therefore run slower than the selectors they were
+//! compiled from, up to about 1.7 times as long in
+//! `examples/bench.rs`, and about as fast as running the plan
+//! itself.  Prefer selectors where a query is known when compiling
+//! and runs often.
+//!
+//! The instructions are grouped in blocks, each ending with `Yield`.
+//! A block runs on a single node and hands every node reaching its
+//! `Yield` to a sink.  Filters continue with the next instruction or
+//! stop, navigation continues with the next instruction once for
+//! every node it finds, and nested plans such as the filter of
+//! `wherein` are blocks of their own.  Stages which only select
+//! after their whole input has been seen, such as `diff` and `and`,
+//! start a block whose input is the result of another block.
+
+use serialize::json::Json;
+use std::borrow::Cow;
+use std::fmt;
+
+use super::{ArithOp,Axis,Cmp,F64Test,Func,JsonPath,Position,Selector,Step};
+use super::{Closure,ExprPlan,Kind,NodeMapper,NodePredicate,Op,PathPredicate,Plan};
+use super::{Flow,NodeSet,Operands,ValueSet,Visit,SINGLETON,aborted,probe};
+use super::{arith_json,ascend_helper,compare_json,concat_json,descend_breadth_helper};
+use super::{descend_helper,has_children,in_filter,json_contains,json_eq,json_len};
+use super::{Locator,sibling_helper,step_matches};
+
+/// Index of a block in a program
+type BlockId = u32;
+
+/// Index of a set in the frame of a block
+type Slot = u32;
+
+/// Instruction of a program
+#[derive(Clone, Debug, PartialEq)]
+enum Instr {
+    // Filters, continuing with the next instruction if the node passes
+    Is(Kind),
+    StringEquals(Box<str>),
+    BooleanEquals(bool),
+    U64Equals(u64),
+    I64Equals(i64),
+    F64Equals(f64),
+    F64Test(F64Test),
+    LenRange(usize, usize),
+    Position(Position),
+    AtDepth(usize),
+    Named(Box<str>),
+    Leaf,
+    Wherein(BlockId),
+    EqualsJson(Box<Json>),
+    ContainsJson(Box<Json>),
+    Compare(Box<(ExprCode, Cmp, ExprCode)>),
+    Filter(Closure<NodePredicate>),
+    FilterPath(Closure<PathPredicate>),
+    Distinct(Slot),
+
+    // Navigation, continuing with the next instruction for each node found
+    At(usize),
+    Key(Box<str>),
+    Child,
+    Parent(Slot),
+    Root(Slot),
+    Sibling(Axis, Slot),
+    Descend(Slot),
+    DescendBreadth(Slot),
+    DescendMax(usize, Slot),
+    DescendOrSelf(Slot),
+    DescendUntil(BlockId, Slot),
+    DescendPrune(BlockId, Slot),
+    Ascend(Slot),
+    FlatMap(Closure<NodeMapper>),
+    Union(BlockId, BlockId, Slot),
+    Intersect(BlockId, BlockId, Slot),
+    UnionAll(Box<[BlockId]>, Slot),
+    IfThenElse(BlockId, BlockId, BlockId),
+    Coalesce(Box<[BlockId]>),
+    UnionValues(BlockId, BlockId, Slot),
+    IntersectValues(BlockId, BlockId, Slot),
+
+    // Aggregates, only found first in a block and running the block
+    // given first on its input
+    Diff(BlockId, BlockId, BlockId),
+    And(BlockId, BlockId, BlockId),
+    Or(BlockId, BlockId, BlockId),
+    AnyOf(BlockId, Box<[BlockId]>),
+    AllOf(BlockId, Box<[BlockId]>),
+    DiffValues(BlockId, BlockId, BlockId),
+
+    /// Hand the node to the sink of the block
+    ///
+    /// This doesn't check whether the query has been aborted, as the
+    /// nodes reaching the end of the program after that are left out
+    /// by `select` anyway.
+    Yield
+}
+
+fn fmt_blocks(f: &mut fmt::Formatter, list: &[BlockId]) -> fmt::Result {
+    f.write_str("[")?;
+    for (i, b) in list.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "@{}", b)?;
+    }
+    f.write_str("]")
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instr::Is(kind) => write!(f, "is {}", kind),
+            Instr::StringEquals(ref comp) => write!(f, "equals {:?}", comp),
+            Instr::BooleanEquals(comp) => write!(f, "equals {}", comp),
+            Instr::U64Equals(comp) => write!(f, "equals {}", comp),
+            Instr::I64Equals(comp) => write!(f, "equals {}", comp),
+            Instr::F64Equals(comp) => write!(f, "equals {:?}", comp),
+            Instr::F64Test(test) => write!(f, "test {}", test),
+            Instr::LenRange(min, max) => write!(f, "len {} {}", min, max),
+            Instr::Position(position) => write!(f, "position {}", position),
+            Instr::AtDepth(depth) => write!(f, "at_depth {}", depth),
+            Instr::Named(ref pattern) => write!(f, "named {:?}", pattern),
+            Instr::Leaf => f.write_str("leaf"),
+            Instr::Wherein(filter) => write!(f, "wherein @{}", filter),
+            Instr::EqualsJson(ref value) => write!(f, "equals_json {}", value),
+            Instr::ContainsJson(ref template) => write!(f, "contains_json {}", template),
+            Instr::Compare(ref c) => write!(f, "compare {} {:?} {}", c.0, c.1, c.2),
+            Instr::Filter(..) => f.write_str("filter .."),
+            Instr::FilterPath(..) => f.write_str("filter_path .."),
+            Instr::Distinct(..) => f.write_str("distinct"),
+            Instr::At(index) => write!(f, "at {}", index),
+            Instr::Key(ref name) => write!(f, "key {:?}", name),
+            Instr::Child => f.write_str("child"),
+            Instr::Parent(..) => f.write_str("parent"),
+            Instr::Root(..) => f.write_str("root"),
+            Instr::Sibling(axis, _) => write!(f, "sibling {}", axis),
+            Instr::Descend(..) => f.write_str("descend"),
+            Instr::DescendBreadth(..) => f.write_str("descend_breadth"),
+            Instr::DescendMax(depth, _) => write!(f, "descend_max {}", depth),
+            Instr::DescendOrSelf(..) => f.write_str("descend_or_self"),
+            Instr::DescendUntil(filter, _) => write!(f, "descend_until @{}", filter),
+            Instr::DescendPrune(filter, _) => write!(f, "descend_prune @{}", filter),
+            Instr::Ascend(..) => f.write_str("ascend"),
+            Instr::FlatMap(..) => f.write_str("flat_map .."),
+            Instr::Union(left, right, _) => write!(f, "union @{} @{}", left, right),
+            Instr::Intersect(left, right, _) => write!(f, "intersect @{} @{}", left, right),
+            Instr::UnionAll(ref list, _) => {
+                f.write_str("union_all ")?;
+                fmt_blocks(f, list)
+            },
+            Instr::IfThenElse(cond, then, otherwise) =>
+                write!(f, "if_then_else @{} @{} @{}", cond, then, otherwise),
+            Instr::Coalesce(ref list) => {
+                f.write_str("coalesce ")?;
+                fmt_blocks(f, list)
+            },
+            Instr::UnionValues(left, right, _) => write!(f, "union_values @{} @{}", left, right),
+            Instr::IntersectValues(left, right, _) => write!(f, "intersect_values @{} @{}", left, right),
+            Instr::Diff(inner, left, right) => write!(f, "diff @{} @{} @{}", inner, left, right),
+            Instr::And(inner, left, right) => write!(f, "and @{} @{} @{}", inner, left, right),
+            Instr::Or(inner, left, right) => write!(f, "or @{} @{} @{}", inner, left, right),
+            Instr::AnyOf(inner, ref list) => {
+                write!(f, "any_of @{} ", inner)?;
+                fmt_blocks(f, list)
+            },
+            Instr::AllOf(inner, ref list) => {
+                write!(f, "all_of @{} ", inner)?;
+                fmt_blocks(f, list)
+            },
+            Instr::DiffValues(inner, left, right) => write!(f, "diff_values @{} @{} @{}", inner, left, right),
+            Instr::Yield => f.write_str("yield")
+        }
+    }
+}
+
+/// Compiled expression of a program
+#[derive(Clone, Debug, PartialEq)]
+enum ExprCode {
+    Select(BlockId),
+    Lit(Json),
+    Map(BlockId, Box<ExprCode>),
+    Arith(Box<ExprCode>, ArithOp, Box<ExprCode>),
+    Concat(Box<ExprCode>, Box<ExprCode>),
+    Apply(Box<ExprCode>, Func)
+}
+
+impl fmt::Display for ExprCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExprCode::Select(block) => write!(f, "@{}", block),
+            ExprCode::Lit(ref value) => write!(f, "{}", value),
+            ExprCode::Map(block, ref expr) => write!(f, "map(@{}, {})", block, expr),
+            ExprCode::Arith(ref left, op, ref right) => write!(f, "{}({}, {})", op, left, right),
+            ExprCode::Concat(ref left, ref right) => write!(f, "concat({}, {})", left, right),
+            ExprCode::Apply(ref inner, func) => write!(f, "{}({})", func, inner)
+        }
+    }
+}
+
+/// Location and frame size of a block
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Block {
+    start: u32,
+    node_sets: u32,
+    value_sets: u32
+}
+
+/// Selector compiled into instructions
+///
+/// A program selects the same nodes as the plan it was compiled
+/// from.  `Display` writes its instructions one per line, each
+/// block headed by its number, and nested blocks are referred to as
+/// `@` followed by that number.  When traced or profiled a program
+/// appears as a single stage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    code: Vec<Instr>,
+    blocks: Vec<Block>,
+    entry: BlockId
+}
+
+impl Program {
+    /// Compile `plan` into a program
+    pub fn compile(plan: &Plan) -> Program {
+        let mut compiler = Compiler { code: Vec::new(), blocks: Vec::new() };
+        let entry = compiler.block(plan.ops());
+        Program { code: compiler.code, blocks: compiler.blocks, entry }
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut blocks = self.blocks.iter().enumerate().peekable();
+        for (pc, instr) in self.code.iter().enumerate() {
+            match blocks.peek() {
+                Some(&(i, b)) if b.start as usize == pc => {
+                    writeln!(f, "@{}:", i)?;
+                    blocks.next();
+                },
+                _ => ()
+            }
+            writeln!(f, "  {:4}  {}", pc, instr)?;
+        }
+        Ok(())
+    }
+}
+
+struct Compiler {
+    code: Vec<Instr>,
+    blocks: Vec<Block>
+}
+
+fn is_aggregate(op: &Op) -> bool {
+    matches!(*op, Op::Diff(..) | Op::And(..) | Op::Or(..) | Op::AnyOf(..) |
+             Op::AllOf(..) | Op::DiffValues(..))
+}
+
+impl Block {
+    fn node_sets(&mut self, count: u32) -> Slot {
+        self.node_sets += count;
+        self.node_sets - count
+    }
+
+    fn value_sets(&mut self, count: u32) -> Slot {
+        self.value_sets += count;
+        self.value_sets - count
+    }
+}
+
+impl Compiler {
+    /// Compile `ops` into a block, compiling the blocks it refers to
+    /// first so that its instructions are contiguous
+    fn block(&mut self, ops: &[Op]) -> BlockId {
+        let mut block = Block { start: 0, node_sets: 0, value_sets: 0 };
+        let mut code = Vec::with_capacity(ops.len() + 1);
+        let rest = match ops.iter().rposition(is_aggregate) {
+            Some(i) => {
+                let inner = self.block(&ops[..i]);
+                code.push(self.aggregate(inner, &ops[i]));
+                &ops[i + 1..]
+            },
+            None => ops
+        };
+        for op in rest {
+            let instr = self.instr(op, &mut block);
+            code.push(instr);
+        }
+        code.push(Instr::Yield);
+        block.start = self.code.len() as u32;
+        self.code.extend(code);
+        self.blocks.push(block);
+        (self.blocks.len() - 1) as BlockId
+    }
+
+    fn blocks(&mut self, list: &[Plan]) -> Box<[BlockId]> {
+        list.iter().map(|p| self.block(p.ops())).collect()
+    }
+
+    fn aggregate(&mut self, inner: BlockId, op: &Op) -> Instr {
+        match *op {
+            Op::Diff(ref left, ref right) =>
+                Instr::Diff(inner, self.block(left.ops()), self.block(right.ops())),
+            Op::And(ref left, ref right) =>
+                Instr::And(inner, self.block(left.ops()), self.block(right.ops())),
+            Op::Or(ref left, ref right) =>
+                Instr::Or(inner, self.block(left.ops()), self.block(right.ops())),
+            Op::AnyOf(ref list) => Instr::AnyOf(inner, self.blocks(list)),
+            Op::AllOf(ref list) => Instr::AllOf(inner, self.blocks(list)),
+            Op::DiffValues(ref left, ref right) =>
+                Instr::DiffValues(inner, self.block(left.ops()), self.block(right.ops())),
+            _ => unreachable!()
+        }
+    }
+
+    fn instr(&mut self, op: &Op, block: &mut Block) -> Instr {
+        match *op {
+            Op::Is(kind) => Instr::Is(kind),
+            Op::StringEquals(ref comp) => Instr::StringEquals(comp.as_str().into()),
+            Op::BooleanEquals(comp) => Instr::BooleanEquals(comp),
+            Op::U64Equals(comp) => Instr::U64Equals(comp),
+            Op::I64Equals(comp) => Instr::I64Equals(comp),
+            Op::F64Equals(comp) => Instr::F64Equals(comp),
+            Op::F64Test(test) => Instr::F64Test(test),
+            Op::LenRange(min, max) => Instr::LenRange(min, max),
+            Op::At(index) => Instr::At(index),
+            Op::Key(ref name) => Instr::Key(name.as_str().into()),
+            Op::Child => Instr::Child,
+            Op::Parent => Instr::Parent(block.node_sets(1)),
+            Op::Root => Instr::Root(block.node_sets(1)),
+            Op::Sibling(axis) => Instr::Sibling(axis, block.node_sets(1)),
+            Op::Position(position) => Instr::Position(position),
+            Op::AtDepth(depth) => Instr::AtDepth(depth),
+            Op::Named(ref pattern) => Instr::Named(pattern.as_str().into()),
+            Op::Leaf => Instr::Leaf,
+            Op::Descend => Instr::Descend(block.node_sets(1)),
+            Op::DescendBreadth => Instr::DescendBreadth(block.node_sets(1)),
+            Op::DescendMax(depth) => Instr::DescendMax(depth, block.node_sets(1)),
+            Op::DescendOrSelf => Instr::DescendOrSelf(block.node_sets(2)),
+            Op::DescendUntil(ref filter) => Instr::DescendUntil(self.block(filter.ops()), block.node_sets(1)),
+            Op::DescendPrune(ref filter) => Instr::DescendPrune(self.block(filter.ops()), block.node_sets(1)),
+            Op::Ascend => Instr::Ascend(block.node_sets(1)),
+            Op::Wherein(ref filter) => Instr::Wherein(self.block(filter.ops())),
+            Op::EqualsJson(ref value) => Instr::EqualsJson(Box::new(value.clone())),
+            Op::ContainsJson(ref template) => Instr::ContainsJson(Box::new(template.clone())),
+            Op::Compare(ref left, op, ref right) =>
+                Instr::Compare(Box::new((self.expr(left), op, self.expr(right)))),
+            Op::Filter(ref pred) => Instr::Filter(pred.clone()),
+            Op::FilterPath(ref pred) => Instr::FilterPath(pred.clone()),
+            Op::FlatMap(ref map) => Instr::FlatMap(map.clone()),
+            Op::Union(ref left, ref right) =>
+                Instr::Union(self.block(left.ops()), self.block(right.ops()), block.node_sets(1)),
+            Op::Intersect(ref left, ref right) =>
+                Instr::Intersect(self.block(left.ops()), self.block(right.ops()), block.node_sets(2)),
+            Op::UnionAll(ref list) => Instr::UnionAll(self.blocks(list), block.node_sets(1)),
+            Op::IfThenElse(ref cond, ref then, ref otherwise) =>
+                Instr::IfThenElse(self.block(cond.ops()), self.block(then.ops()), self.block(otherwise.ops())),
+            Op::Coalesce(ref list) => Instr::Coalesce(self.blocks(list)),
+            Op::Distinct => Instr::Distinct(block.value_sets(1)),
+            Op::UnionValues(ref left, ref right) =>
+                Instr::UnionValues(self.block(left.ops()), self.block(right.ops()), block.value_sets(1)),
+            Op::IntersectValues(ref left, ref right) =>
+                Instr::IntersectValues(self.block(left.ops()), self.block(right.ops()), block.value_sets(3)),
+            Op::Diff(..) | Op::And(..) | Op::Or(..) | Op::AnyOf(..) | Op::AllOf(..) |
+            Op::DiffValues(..) => unreachable!()
+        }
+    }
+
+    fn expr(&mut self, expr: &ExprPlan) -> ExprCode {
+        match *expr {
+            ExprPlan::Select(ref plan) => ExprCode::Select(self.block(plan.ops())),
+            ExprPlan::Lit(ref value) => ExprCode::Lit(value.clone()),
+            ExprPlan::Map(ref plan, ref expr) => ExprCode::Map(self.block(plan.ops()), Box::new(self.expr(expr))),
+            ExprPlan::Arith(ref left, op, ref right) =>
+                ExprCode::Arith(Box::new(self.expr(left)), op, Box::new(self.expr(right))),
+            ExprPlan::Concat(ref left, ref right) =>
+                ExprCode::Concat(Box::new(self.expr(left)), Box::new(self.expr(right))),
+            ExprPlan::Apply(ref inner, func) => ExprCode::Apply(Box::new(self.expr(inner)), func)
+        }
+    }
+}
+
+/// Closure receiving the nodes reaching the end of a block
+type Sink<'s,'a> = dyn for<'c> FnMut(&JsonPath<'a,'c>) + 's;
+
+/// Sets of a running block
+///
+/// Every stage keeping track of nodes has sets of its own, created
+/// the first time they're used.  The slots for them are only
+/// allocated then too, so that running a block which keeps no sets,
+/// such as a filter, costs no allocation.
+struct Frame<'a> {
+    block: Block,
+    nodes: Vec<Option<NodeSet>>,
+    values: Vec<Option<ValueSet<'a>>>
+}
+
+impl<'a> Frame<'a> {
+    fn new(block: Block) -> Frame<'a> {
+        Frame { block, nodes: Vec::new(), values: Vec::new() }
+    }
+
+    fn node_slot(&mut self, slot: Slot) -> &mut Option<NodeSet> {
+        if self.nodes.is_empty() {
+            self.nodes.resize_with(self.block.node_sets as usize, || None)
+        }
+        &mut self.nodes[slot as usize]
+    }
+
+    fn nodes(&mut self, slot: Slot) -> &mut NodeSet {
+        self.node_slot(slot).get_or_insert_with(NodeSet::new)
+    }
+
+    fn values(&mut self, slot: Slot) -> &mut ValueSet<'a> {
+        if self.values.is_empty() {
+            self.values.resize_with(self.block.value_sets as usize, || None)
+        }
+        self.values[slot as usize].get_or_insert_with(ValueSet::new)
+    }
+
+    /// Take out the set in `slot`, for a helper which needs it while
+    /// the frame is in use
+    fn take_nodes(&mut self, slot: Slot) -> NodeSet {
+        self.node_slot(slot).take().unwrap_or_else(NodeSet::new)
+    }
+
+    fn put_nodes(&mut self, slot: Slot, set: NodeSet) {
+        *self.node_slot(slot) = Some(set)
+    }
+}
+
+impl Selector for Program {
+    fn select<'a,'b,F>(&self, input: &JsonPath<'a,'b>, f: F)
+                       where F: for<'c> FnMut(&JsonPath<'a,'c>) {
+        let mut f = probe(self, input, Flow::Stream, f);
+        self.run(self.entry, input, &mut f)
+    }
+}
+
+impl Program {
+    fn run<'a,'b>(&self, block: BlockId, input: &JsonPath<'a,'b>, f: &mut Sink<'_,'a>) {
+        let block = self.blocks[block as usize];
+        let mut frame = Frame::new(block);
+        self.step(block.start as usize, input, &mut frame, f)
+    }
+
+    /// Check whether `filter` selects any nodes from `input`, like
+    /// `selects_any`
+    fn selects_any<'a,'b>(&self, filter: BlockId, input: &JsonPath<'a,'b>) -> bool {
+        let mut matches = false;
+        in_filter(|| self.run(filter, input, &mut |_| matches = true));
+        matches
+    }
+
+    /// Run the instructions from `pc` on `x`
+    fn step<'a,'b>(&self, mut pc: usize, x: &JsonPath<'a,'b>, frame: &mut Frame<'a>, f: &mut Sink<'_,'a>) {
+        loop {
+            let next = pc + 1;
+            match self.code[pc] {
+                Instr::Is(kind) => if Kind::of(x.node()) != kind {
+                    return
+                },
+                Instr::StringEquals(ref comp) => match x.node() {
+                    Json::String(s) if **comp == **s => (),
+                    _ => return
+                },
+                Instr::BooleanEquals(comp) => match x.node() {
+                    &Json::Boolean(b) if b == comp => (),
+                    _ => return
+                },
+                Instr::U64Equals(comp) => match x.node() {
+                    &Json::U64(b) if b == comp => (),
+                    _ => return
+                },
+                Instr::I64Equals(comp) => match x.node() {
+                    &Json::I64(b) if b == comp => (),
+                    _ => return
+                },
+                Instr::F64Equals(comp) => match x.node() {
+                    &Json::F64(b) if b == comp => (),
+                    _ => return
+                },
+                Instr::F64Test(test) => match x.node() {
+                    &Json::F64(v) if test.test(v) => (),
+                    _ => return
+                },
+                Instr::Leaf => if has_children(x.node()) {
+                    return
+                },
+                Instr::LenRange(..) | Instr::Position(..) | Instr::AtDepth(..) | Instr::Named(..) |
+                Instr::Wherein(..) | Instr::EqualsJson(..) | Instr::ContainsJson(..) |
+                Instr::Compare(..) | Instr::Filter(..) | Instr::FilterPath(..) |
+                Instr::Distinct(..) => if !self.passes(pc, x, frame) {
+                    return
+                },
+                Instr::At(index) => {
+                    match x.node() {
+                        Json::Array(v) if v.len() > index =>
+                            self.step(next, &x.descendant(&v[index], Step::Index(index)), frame, f),
+                        _ => ()
+                    }
+                    return
+                },
+                Instr::Key(ref name) => {
+                    if let Json::Object(m) = x.node() {
+                        if let Some((k, e)) = m.get_key_value(&**name) {
+                            self.step(next, &x.descendant(e, Step::Key(k)), frame, f)
+                        }
+                    }
+                    return
+                },
+                Instr::Child => {
+                    match x.node() {
+                        Json::Object(m) => {
+                            for (k, child) in m.iter() {
+                                self.step(next, &x.descendant(child, Step::Key(k)), frame, f)
+                            }
+                        },
+                        Json::Array(v) => {
+                            for (i, child) in v.iter().enumerate() {
+                                self.step(next, &x.descendant(child, Step::Index(i)), frame, f)
+                            }
+                        },
+                        _ => ()
+                    }
+                    return
+                },
+                Instr::Yield => return f(x),
+                _ => return self.branch(pc, x, frame, f)
+            }
+            pc = next;
+        }
+    }
+
+    /// Check whether `x` passes the filter at `pc`, for `step`, when
+    /// it takes more than a look at the node
+    ///
+    /// This and `branch` are kept out of `step`, which recurses for
+    /// every node walked, so that the instructions most queries run
+    /// take as little stack as possible.
+    #[inline(never)]
+    fn passes<'a,'b>(&self, pc: usize, x: &JsonPath<'a,'b>, frame: &mut Frame<'a>) -> bool {
+        match self.code[pc] {
+            Instr::LenRange(min, max) => matches!(json_len(x.node()), Some(len) if len >= min && len <= max),
+            Instr::Position(position) => matches!(x.position(), Some((i, len)) if position.test(i, len)),
+            Instr::AtDepth(depth) => x.depth() == depth && x.step() != Some(Step::Synthetic),
+            Instr::Named(ref pattern) => step_matches(pattern, x.step()),
+            Instr::Wherein(filter) => self.selects_any(filter, x),
+            Instr::EqualsJson(ref value) => json_eq(x.node(), value),
+            Instr::ContainsJson(ref template) => json_contains(x.node(), template),
+            Instr::Compare(ref c) => {
+                let (ref left, op, ref right) = **c;
+                let mut rights = Operands::new();
+                self.eval(right, x, &mut |r| rights.push(r));
+                let mut matches = false;
+                self.eval(left, x, &mut |l| {
+                    if !matches && !aborted() {
+                        matches = rights.any(|r| op.test(compare_json(&l, r)))
+                    }
+                });
+                matches
+            },
+            Instr::Filter(ref pred) => (**pred)(x.node()),
+            Instr::FilterPath(ref pred) => (**pred)(x),
+            Instr::Distinct(slot) => frame.values(slot).insert(x.node()),
+            _ => unreachable!()
+        }
+    }
+
+    /// Run the instruction at `pc` on `x` for `step`, when it walks
+    /// anywhere but down from `x`
+    #[inline(never)]
+    fn branch<'a,'b>(&self, pc: usize, x: &JsonPath<'a,'b>, frame: &mut Frame<'a>, f: &mut Sink<'_,'a>) {
+        let next = pc + 1;
+        match self.code[pc] {
+            Instr::Parent(slot) => {
+                if let Some(p) = x.parent() {
+                    if frame.nodes(slot).insert(p.node()) {
+                        self.step(next, &p, frame, f)
+                    }
+                }
+            },
+            Instr::Root(slot) => {
+                let mut current = *x;
+                while let Some(p) = current.parent() {
+                    current = p
+                }
+                if frame.nodes(slot).insert(current.node()) {
+                    self.step(next, &current, frame, f)
+                }
+            },
+            Instr::Sibling(axis, slot) => {
+                sibling_helper(x, axis, |y| {
+                    if frame.nodes(slot).insert(y.node()) {
+                        self.step(next, y, frame, f)
+                    }
+                });
+            },
+            Instr::Descend(slot) => {
+                let mut seen = frame.take_nodes(slot);
+                descend_helper(x, &mut seen, usize::MAX, |_| Visit::Enter, |y| self.step(next, y, frame, f));
+                frame.put_nodes(slot, seen)
+            },
+            Instr::DescendBreadth(slot) => {
+                let mut seen = frame.take_nodes(slot);
+                descend_breadth_helper(x, &mut seen, |y| self.step(next, y, frame, f));
+                frame.put_nodes(slot, seen)
+            },
+            Instr::DescendMax(0, _) => (),
+            Instr::DescendMax(depth, slot) => {
+                let mut seen = NodeSet::new();
+                descend_helper(x, &mut seen, depth, |_| Visit::Enter, |y| {
+                    if frame.nodes(slot).insert(y.node()) {
+                        self.step(next, y, frame, f)
+                    }
+                });
+            },
+            Instr::DescendOrSelf(slot) => {
+                let mut seen = frame.take_nodes(slot);
+                if frame.nodes(slot + 1).insert(x.node()) {
+                    self.step(next, x, frame, f)
+                }
+                descend_helper(x, &mut seen, usize::MAX, |_| Visit::Enter, |y| {
+                    if frame.nodes(slot + 1).insert(y.node()) {
+                        self.step(next, y, frame, f)
+                    }
+                });
+                frame.put_nodes(slot, seen)
+            },
+            Instr::DescendUntil(filter, slot) => {
+                let mut seen = frame.take_nodes(slot);
+                descend_helper(x, &mut seen, usize::MAX, |y| {
+                    if self.selects_any(filter, y) {
+                        Visit::Select
+                    } else {
+                        Visit::Enter
+                    }
+                }, |y| self.step(next, y, frame, f));
+                frame.put_nodes(slot, seen)
+            },
+            Instr::DescendPrune(filter, slot) => {
+                let mut seen = frame.take_nodes(slot);
+                descend_helper(x, &mut seen, usize::MAX, |y| {
+                    if self.selects_any(filter, y) {
+                        Visit::Skip
+                    } else {
+                        Visit::Enter
+                    }
+                }, |y| self.step(next, y, frame, f));
+                frame.put_nodes(slot, seen)
+            },
+            Instr::Ascend(slot) => {
+                let mut seen = frame.take_nodes(slot);
+                ascend_helper(x, &mut seen, |y| self.step(next, y, frame, f));
+                frame.put_nodes(slot, seen)
+            },
+            Instr::FlatMap(ref map) => {
+                let mut locator = Locator::new();
+                for target in (**map)(x.node()) {
+                    locator.locate(x, target, |y| self.step(next, y, frame, f))
+                }
+            },
+            Instr::Union(left, right, slot) => {
+                for &block in &[left, right] {
+                    self.run(block, x, &mut |y| {
+                        if frame.nodes(slot).insert(y.node()) {
+                            self.step(next, y, frame, f)
+                        }
+                    })
+                }
+            },
+            Instr::Intersect(left, right, slot) => {
+                for &(block, mine, theirs) in &[(left, slot, slot + 1), (right, slot + 1, slot)] {
+                    self.run(block, x, &mut |y| {
+                        let j = y.node();
+                        frame.nodes(mine).insert(j);
+                        if frame.nodes(theirs).contains(j) {
+                            self.step(next, y, frame, f)
+                        }
+                    })
+                }
+            },
+            Instr::UnionAll(ref list, slot) => {
+                for &block in list.iter() {
+                    self.run(block, x, &mut |y| {
+                        if frame.nodes(slot).insert(y.node()) {
+                            self.step(next, y, frame, f)
+                        }
+                    })
+                }
+            },
+            Instr::IfThenElse(cond, then, otherwise) => {
+                let block = if self.selects_any(cond, x) { then } else { otherwise };
+                self.run(block, x, &mut |y| self.step(next, y, frame, f))
+            },
+            Instr::Coalesce(ref list) => {
+                for &block in list.iter() {
+                    let mut found = false;
+                    self.run(block, x, &mut |y| {
+                        found = true;
+                        self.step(next, y, frame, f)
+                    });
+                    if found {
+                        break
+                    }
+                }
+            },
+            Instr::UnionValues(left, right, slot) => {
+                for &block in &[left, right] {
+                    self.run(block, x, &mut |y| {
+                        if frame.values(slot).insert(y.node()) {
+                            self.step(next, y, frame, f)
+                        }
+                    })
+                }
+            },
+            Instr::IntersectValues(left, right, slot) => {
+                for &(block, mine, theirs) in &[(left, slot, slot + 1), (right, slot + 1, slot)] {
+                    self.run(block, x, &mut |y| {
+                        let j = y.node();
+                        frame.values(mine).insert(j);
+                        if frame.values(theirs).contains(j) && frame.values(slot + 2).insert(j) {
+                            self.step(next, y, frame, f)
+                        }
+                    })
+                }
+            },
+            Instr::Diff(inner, left, right) => {
+                let mut seen = NodeSet::new();
+                self.run(inner, x, &mut |y| self.run(right, y, &mut |z| {
+                    seen.insert(z.node());
+                }));
+                self.run(inner, x, &mut |y| self.run(left, y, &mut |z| {
+                    if !seen.contains(z.node()) {
+                        self.step(next, z, frame, f)
+                    }
+                }))
+            },
+            Instr::And(inner, left, right) | Instr::Or(inner, left, right) => {
+                let mut found_left = false;
+                let mut found_right = false;
+                self.run(inner, x, &mut |y| {
+                    self.run(left, y, &mut |_| found_left = true);
+                    self.run(right, y, &mut |_| found_right = true)
+                });
+                let found = match self.code[pc] {
+                    Instr::And(..) => found_left && found_right,
+                    _ => found_left || found_right
+                };
+                if found {
+                    self.step(next, &x.descendant(&SINGLETON, Step::Synthetic), frame, f)
+                }
+            },
+            Instr::AnyOf(inner, ref list) => {
+                let mut found = false;
+                self.run(inner, x, &mut |y| {
+                    for &block in list.iter() {
+                        if found {
+                            break
+                        }
+                        self.run(block, y, &mut |_| found = true)
+                    }
+                });
+                if found {
+                    self.step(next, &x.descendant(&SINGLETON, Step::Synthetic), frame, f)
+                }
+            },
+            Instr::AllOf(inner, ref list) => {
+                let mut found = vec![false; list.len()];
+                self.run(inner, x, &mut |y| {
+                    for (&block, found) in list.iter().zip(found.iter_mut()) {
+                        if !*found {
+                            self.run(block, y, &mut |_| *found = true)
+                        }
+                    }
+                });
+                if found.iter().all(|&found| found) {
+                    self.step(next, &x.descendant(&SINGLETON, Step::Synthetic), frame, f)
+                }
+            },
+            Instr::DiffValues(inner, left, right) => {
+                let mut seen = ValueSet::new();
+                let mut selected = ValueSet::new();
+                self.run(inner, x, &mut |y| self.run(right, y, &mut |z| {
+                    seen.insert(z.node());
+                }));
+                self.run(inner, x, &mut |y| self.run(left, y, &mut |z| {
+                    let j = z.node();
+                    if !seen.contains(j) && selected.insert(j) {
+                        self.step(next, z, frame, f)
+                    }
+                }))
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn eval<'a,'b>(&self, expr: &ExprCode, input: &JsonPath<'a,'b>, f: &mut dyn FnMut(Cow<'a,Json>)) {
+        match *expr {
+            ExprCode::Select(block) => self.run(block, input, &mut |x| f(Cow::Borrowed(x.node()))),
+            ExprCode::Lit(ref value) => f(Cow::Owned(value.clone())),
+            ExprCode::Map(block, ref expr) => self.run(block, input, &mut |x| self.eval(expr, x, f)),
+            ExprCode::Arith(ref left, op, ref right) => {
+                let mut rights = Operands::new();
+                self.eval(right, input, &mut |r| rights.push(r));
+                self.eval(left, input, &mut |l| {
+                    rights.for_each(|r| {
+                        if let Some(v) = arith_json(op, &l, r) {
+                            f(Cow::Owned(v))
+                        }
+                    })
+                })
+            },
+            ExprCode::Concat(ref left, ref right) => {
+                let mut rights = Operands::new();
+                self.eval(right, input, &mut |r| rights.push(r));
+                self.eval(left, input, &mut |l| {
+                    rights.for_each(|r| {
+                        if let Some(v) = concat_json(&l, r) {
+                            f(Cow::Owned(v))
+                        }
+                    })
+                })
+            },
+            ExprCode::Apply(ref inner, func) => self.eval(inner, input, &mut |v| {
+                if let Some(v) = func.apply(v) {
+                    f(v)
+                }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{at,child,descend,descend_or_self,descend_prune,descend_until,key,node,object,root};
+    use super::super::{uint64,union,Cmp,Expr,JsonExt,Plan,Selector,ToPlan};
+    use serialize::json::Json;
+
+    fn check<S:Selector+ToPlan<Output=Plan>>(json: &Json, sel: S) {
+        let program = sel.to_plan().compile();
+        assert_eq!(json.query(program), json.query(sel));
+    }
+
+    #[test]
+    fn run_program() {
+        let json: Json = r#"{"debug": true, "a": [{"b": "x"}, {"b": 2, "c": null}, {"b": 2}], "d": {"b": "y"}}"#.parse().unwrap();
+        check(&json, descend().key("b").parent());
+        check(&json, descend().wherein(key("b").string()).wherein(root().key("debug")));
+        check(&json, union(key("a").child(), key("d")).key("b"));
+        check(&json, key("a").child().compare(key("b"), Cmp::Eq, key("b").add(uint64().equals(0))));
+        check(&json, key("a").child().and(key("b"), key("c")).root().key("d"));
+        check(&json, key("a").diff(child(), child().wherein(key("c"))).key("b"));
+        check(&json, key("a").child().any_of([key("c"), key("z")]));
+        check(&json, key("a").child().coalesce((key("c"), key("b"))));
+        check(&json, key("a").child().key("b").following_siblings());
+        check(&json, descend_or_self().key("b").distinct());
+        check(&json, descend_until(object()).child());
+        check(&json, key("a").descend_max(0));
+        check(&json, key("a").descend_max(2).string());
+        check(&json, descend_prune(key("c")).string());
+        check(&json, key("a").at(1).key("c").ascend());
+        check(&json, key("a").child().union_values(key("b"), node().key("c")));
+        check(&json, key("a").child().intersect_values(key("b"), key("b")));
+        check(&json, key("a").diff_values(child().key("b"), at(0).key("b")));
+        check(&json, descend().filter(|j| j.is_null()).parent().key("b"));
+
+        let program = key("a").child().wherein(key("b").string()).to_plan().compile();
+        assert_eq!(program.to_string(), "@0:\n     0  key \"b\"\n     1  is string\n     2  yield\n\
+                                         @1:\n     3  key \"a\"\n     4  child\n     5  wherein @0\n     6  yield\n");
+    }
+}